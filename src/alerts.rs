@@ -0,0 +1,570 @@
+use crate::check::Report;
+use crate::collectors::COLLECTORS;
+use crate::{Config, HttpClient, SecretSource, METRICS_PREFIX};
+use anyhow::{Context, Error, Result};
+use log::{error, info};
+use prometheus::proto::{MetricFamily, MetricType};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+type Labels = BTreeMap<String, String>;
+
+// time after which sending a notification to a webhook is given up
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Built-in alerting, for deployments without an Alertmanager.
+///
+/// The configured rules are evaluated against the gathered metrics after every
+/// refresh, and the webhooks are only notified when an alert starts firing or
+/// is resolved, not on every evaluation. Notifications that couldn't be
+/// delivered are sent again after the next evaluation.
+pub(crate) struct Alerting {
+    rules: Vec<Rule>,
+    webhooks: Option<SecretSource>,
+    http: HttpClient,
+    // alerts whose condition currently holds, or whose resolution wasn't
+    // delivered yet, keyed by rule index and labels
+    active: Mutex<HashMap<(usize, Labels), Active>>,
+}
+
+struct Active {
+    // first evaluation the condition held in
+    since: Instant,
+    // whether the firing notification was delivered
+    firing: bool,
+}
+
+impl Alerting {
+    /// Alerting configured by `ALERT_RULES` and `ALERT_WEBHOOKS`, if any rule
+    /// is configured.
+    pub(crate) fn from_config(config: &Config, http: HttpClient) -> Result<Option<Self>> {
+        let rules = parse_rules(&config.alert_rules)?;
+        if rules.is_empty() {
+            return Ok(None);
+        }
+        if let Some(webhooks) = &config.alert_webhooks {
+            parse_webhooks(webhooks.get()?.expose_secret())?;
+        }
+        info!("Evaluating {} alert rule(s)", rules.len());
+
+        Ok(Some(Self {
+            rules,
+            webhooks: config.alert_webhooks.clone(),
+            http,
+            active: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Evaluates the rules against `families` and notifies the webhooks of
+    /// the alerts that started firing or were resolved. Evaluations must not
+    /// run concurrently, or the same notification could be sent twice.
+    pub(crate) async fn evaluate(&self, families: &[MetricFamily]) {
+        let notifications = self.transitions(families, Instant::now());
+        if notifications.is_empty() {
+            return;
+        }
+
+        let webhooks = match self.webhooks() {
+            Ok(webhooks) => webhooks,
+            Err(e) => {
+                error!("Unable to send alert notifications: {:?}", e);
+                return;
+            }
+        };
+        for notification in notifications.iter() {
+            let errors = self.notify(webhooks.as_deref(), notification).await;
+            if errors.is_empty() {
+                self.delivered(notification);
+            }
+            for e in errors {
+                error!("Unable to send alert notification: {:?}", e);
+            }
+        }
+    }
+
+    // alerts that started firing or were resolved since their notification
+    // was last delivered
+    fn transitions(&self, families: &[MetricFamily], now: Instant) -> Vec<Notification<'_>> {
+        let mut active = self.active.lock().unwrap();
+        let mut notifications = Vec::new();
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            let matching = rule.evaluate(families);
+            for (labels, value) in matching.iter() {
+                let alert = active.entry((index, labels.clone())).or_insert(Active {
+                    since: now,
+                    firing: false,
+                });
+                if !alert.firing && now.duration_since(alert.since) >= rule.for_ {
+                    notifications.push(Notification {
+                        status: Status::Firing,
+                        index,
+                        rule,
+                        labels: labels.clone(),
+                        value: Some(*value),
+                    });
+                }
+            }
+
+            active.retain(|(i, labels), alert| {
+                if *i != index || matching.contains_key(labels) {
+                    return true;
+                }
+                if alert.firing {
+                    // kept until the resolution is delivered
+                    notifications.push(Notification {
+                        status: Status::Resolved,
+                        index,
+                        rule,
+                        labels: labels.clone(),
+                        value: None,
+                    });
+                    return true;
+                }
+                false
+            });
+        }
+
+        notifications
+    }
+
+    // records that `notification` was delivered, so that it isn't sent again
+    fn delivered(&self, notification: &Notification<'_>) {
+        let mut active = self.active.lock().unwrap();
+        let key = (notification.index, notification.labels.clone());
+        match notification.status {
+            Status::Firing => {
+                if let Some(alert) = active.get_mut(&key) {
+                    alert.firing = true;
+                }
+            }
+            Status::Resolved => {
+                active.remove(&key);
+            }
+        }
+    }
+
+    // the configured webhooks, `None` if alerts are only logged
+    fn webhooks(&self) -> Result<Option<Vec<(Style, String)>>> {
+        match &self.webhooks {
+            Some(webhooks) => Ok(Some(parse_webhooks(webhooks.get()?.expose_secret())?)),
+            None => Ok(None),
+        }
+    }
+
+    // sends `notification` to every webhook, even if some of them fail, and
+    // returns the errors. The notification is only considered delivered once
+    // every webhook accepted it, duplicates being better than lost alerts.
+    async fn notify(
+        &self,
+        webhooks: Option<&[(Style, String)]>,
+        notification: &Notification<'_>,
+    ) -> Vec<Error> {
+        let webhooks = match webhooks {
+            Some(webhooks) => webhooks,
+            None => {
+                info!("{}", notification.render(Style::Slack));
+                return Vec::new();
+            }
+        };
+
+        let mut errors = Vec::new();
+        for (style, url) in webhooks {
+            let body = serde_json::json!({ "text": notification.render(*style) });
+            let result = async {
+                self.http
+                    .send(self.http.post(url).timeout(NOTIFY_TIMEOUT).json(&body))
+                    .await?
+                    .error_for_status()?;
+                Ok::<_, Error>(())
+            }
+            .await;
+            if let Err(e) = result {
+                errors.push(e.context(format!("{} webhook rejected the notification", style)));
+            }
+        }
+        errors
+    }
+}
+
+/// A threshold rule, e.g. `gha_runner_online == 0 for 10m`.
+///
+/// The left hand side is either a metric or the ratio of two metrics, which
+/// is computed between the series with the same labels.
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    source: String,
+    metric: String,
+    divisor: Option<String>,
+    op: Op,
+    threshold: f64,
+    for_: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Le,
+    Ge,
+    Lt,
+    Gt,
+}
+
+impl Op {
+    // two character operators first, so `<=` isn't parsed as `<`
+    const ALL: &'static [(&'static str, Op)] = &[
+        ("==", Op::Eq),
+        ("!=", Op::Ne),
+        ("<=", Op::Le),
+        (">=", Op::Ge),
+        ("<", Op::Lt),
+        (">", Op::Gt),
+    ];
+
+    fn holds(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Op::Eq => value == threshold,
+            Op::Ne => value != threshold,
+            Op::Le => value <= threshold,
+            Op::Ge => value >= threshold,
+            Op::Lt => value < threshold,
+            Op::Gt => value > threshold,
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let source = s.trim().to_string();
+        let (condition, for_) = match source.split_once(" for ") {
+            Some((condition, for_)) => (condition, parse_duration(for_.trim())?),
+            None => (source.as_str(), Duration::from_secs(0)),
+        };
+        let (lhs, op, threshold) = Op::ALL
+            .iter()
+            .find_map(|(token, op)| {
+                let (lhs, threshold) = condition.split_once(token)?;
+                Some((lhs, *op, threshold))
+            })
+            .with_context(|| format!("missing comparison operator in alert rule {}", source))?;
+        let threshold = threshold
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid threshold in alert rule {}", source))?;
+        let (metric, divisor) = match lhs.split_once('/') {
+            Some((metric, divisor)) => (metric.trim(), Some(divisor.trim().to_string())),
+            None => (lhs.trim(), None),
+        };
+
+        for name in std::iter::once(metric).chain(divisor.as_deref()) {
+            let known = COLLECTORS
+                .iter()
+                .any(|c| c.metrics.iter().any(|m| m.name == name));
+            anyhow::ensure!(known, "unknown metric {} in alert rule {}", name, source);
+        }
+
+        Ok(Self {
+            metric: metric.to_string(),
+            divisor,
+            op,
+            threshold,
+            for_,
+            source,
+        })
+    }
+}
+
+impl Rule {
+    // series for which the condition holds, along with their value
+    fn evaluate(&self, families: &[MetricFamily]) -> HashMap<Labels, f64> {
+        let values = series(families, &self.metric);
+        let divisors = self.divisor.as_ref().map(|d| series(families, d));
+
+        values
+            .into_iter()
+            .filter_map(|(labels, value)| match &divisors {
+                Some(divisors) => match divisors.get(&labels) {
+                    Some(divisor) if *divisor != 0.0 => Some((labels, value / divisor)),
+                    _ => None,
+                },
+                None => Some((labels, value)),
+            })
+            .filter(|(_, value)| self.op.holds(*value, self.threshold))
+            .collect()
+    }
+}
+
+// every series of the metric `name` (without the registry prefix)
+fn series(families: &[MetricFamily], name: &str) -> HashMap<Labels, f64> {
+    let full_name = format!("{}_{}", METRICS_PREFIX, name);
+    families
+        .iter()
+        .filter(|family| family.get_name() == full_name)
+        .flat_map(|family| {
+            let kind = family.get_field_type();
+            family.get_metric().iter().map(move |metric| {
+                let labels = metric
+                    .get_label()
+                    .iter()
+                    .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                    .collect();
+                let value = match kind {
+                    MetricType::COUNTER => metric.get_counter().get_value(),
+                    _ => metric.get_gauge().get_value(),
+                };
+                (labels, value)
+            })
+        })
+        .collect()
+}
+
+fn parse_rules(rules: &str) -> Result<Vec<Rule>> {
+    rules
+        .split(';')
+        .filter(|rule| !rule.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+// durations like `30s`, `10m` or `1h`
+fn parse_duration(s: &str) -> Result<Duration> {
+    let (value, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let value: u64 = value
+        .parse()
+        .with_context(|| format!("invalid duration {}", s))?;
+    let secs = match unit {
+        "s" => value,
+        "m" => value * 60,
+        "h" => value * 3600,
+        _ => anyhow::bail!("invalid duration {}, expected e.g. 30s, 10m or 1h", s),
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+/// Message formatting of a webhook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Style {
+    Zulip,
+    Slack,
+}
+
+impl fmt::Display for Style {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Style::Zulip => write!(f, "zulip"),
+            Style::Slack => write!(f, "slack"),
+        }
+    }
+}
+
+// webhooks like "zulip=https://...,slack=https://..."
+fn parse_webhooks(webhooks: &str) -> Result<Vec<(Style, String)>> {
+    webhooks
+        .split(',')
+        .map(str::trim)
+        .filter(|webhook| !webhook.is_empty())
+        .map(|webhook| {
+            // don't print the url in errors, it usually contains a secret
+            let (style, url) = webhook
+                .split_once('=')
+                .context("expected webhooks in the style=url format")?;
+            let style = match style {
+                "zulip" => Style::Zulip,
+                "slack" => Style::Slack,
+                _ => anyhow::bail!("unknown webhook style {}, expected zulip or slack", style),
+            };
+            Ok((style, url.to_string()))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Firing,
+    Resolved,
+}
+
+#[derive(Debug)]
+struct Notification<'a> {
+    status: Status,
+    // index of the rule, which identifies the alert along with the labels
+    index: usize,
+    rule: &'a Rule,
+    labels: Labels,
+    // value of the series when the alert started firing
+    value: Option<f64>,
+}
+
+impl Notification<'_> {
+    fn render(&self, style: Style) -> String {
+        let (emoji, status) = match (self.status, style) {
+            (Status::Firing, _) => (":red_circle:", "FIRING"),
+            (Status::Resolved, Style::Zulip) => (":green_circle:", "RESOLVED"),
+            (Status::Resolved, Style::Slack) => (":large_green_circle:", "RESOLVED"),
+        };
+        // zulip uses commonmark, slack its own mrkdwn
+        let bold = match style {
+            Style::Zulip => "**",
+            Style::Slack => "*",
+        };
+
+        let mut text = format!(
+            "{} {b}[{}]{b} `{}`",
+            emoji,
+            status,
+            self.rule.source,
+            b = bold
+        );
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, value))
+            .collect();
+        if !labels.is_empty() {
+            text.push_str(&format!("\n{}", labels.join(", ")));
+        }
+        if let Some(value) = self.value {
+            text.push_str(&format!(" (value: {})", value));
+        }
+        text
+    }
+}
+
+// validate the alert rules and webhooks, see `monitorbot check-config`
+pub(crate) fn check_config(config: &Config, report: &mut Report) {
+    report.section("Alerting (ALERT_RULES, ALERT_WEBHOOKS)");
+
+    match parse_rules(&config.alert_rules) {
+        Ok(rules) if rules.is_empty() => report.ok("ALERT_RULES", "built-in alerting disabled"),
+        Ok(rules) => report.ok("ALERT_RULES", format!("{} rule(s)", rules.len())),
+        Err(e) => report.failure("ALERT_RULES", &e),
+    }
+
+    match &config.alert_webhooks {
+        Some(webhooks) => match webhooks
+            .get()
+            .and_then(|webhooks| parse_webhooks(webhooks.expose_secret()))
+        {
+            Ok(webhooks) => report.ok("ALERT_WEBHOOKS", format!("{} webhook(s)", webhooks.len())),
+            Err(e) => report.failure("ALERT_WEBHOOKS", &e),
+        },
+        None if !config.alert_rules.trim().is_empty() => report.warning(
+            "ALERT_WEBHOOKS",
+            "no webhook configured, alerts will only be logged",
+        ),
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_webhooks, Alerting, Notification, Op, Rule, Status, Style};
+    use crate::HttpClient;
+    use prometheus::{IntGauge, Opts, Registry};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn parse_rules() {
+        let rule: Rule = "github_rate_limit_remaining / github_rate_limit_limit <= 0.1 for 10m"
+            .parse()
+            .unwrap();
+        assert_eq!(rule.metric, "github_rate_limit_remaining");
+        assert_eq!(rule.divisor.as_deref(), Some("github_rate_limit_limit"));
+        assert_eq!(rule.op, Op::Le);
+        assert_eq!(rule.threshold, 0.1);
+        assert_eq!(rule.for_, Duration::from_secs(600));
+
+        assert!("gha_runner_online".parse::<Rule>().is_err());
+        assert!("gha_runner_missing == 0".parse::<Rule>().is_err());
+        assert!("gha_runner_online == 0 for 10 minutes"
+            .parse::<Rule>()
+            .is_err());
+
+        let webhooks =
+            parse_webhooks("zulip=https://z/?api_key=a&stream=b, slack=https://s").unwrap();
+        assert_eq!(
+            webhooks[0],
+            (Style::Zulip, "https://z/?api_key=a&stream=b".into())
+        );
+        assert_eq!(webhooks[1], (Style::Slack, "https://s".into()));
+    }
+
+    #[test]
+    fn alerts_fire_once_and_resolve() {
+        let registry = Registry::new_custom(Some("monitorbot".into()), None).unwrap();
+        let online = IntGauge::with_opts(
+            Opts::new("gha_runner_online", "runner is online").const_label("runner", "r1"),
+        )
+        .unwrap();
+        registry.register(Box::new(online.clone())).unwrap();
+
+        let alerting = Alerting {
+            rules: vec!["gha_runner_online == 0 for 10m".parse().unwrap()],
+            webhooks: None,
+            http: HttpClient::new().unwrap(),
+            active: Mutex::new(HashMap::new()),
+        };
+        let start = Instant::now();
+        let statuses = |minutes: u64, deliver: bool| {
+            let notifications = alerting.transitions(
+                &registry.gather(),
+                start + Duration::from_secs(minutes * 60),
+            );
+            if deliver {
+                for notification in notifications.iter() {
+                    alerting.delivered(notification);
+                }
+            }
+            notifications.iter().map(|n| n.status).collect::<Vec<_>>()
+        };
+
+        // pending until the condition held for 10 minutes
+        assert!(statuses(0, true).is_empty());
+        assert!(statuses(5, true).is_empty());
+        // undelivered notifications are sent again
+        assert_eq!(statuses(10, false), vec![Status::Firing]);
+        assert_eq!(statuses(11, true), vec![Status::Firing]);
+        // repeated notifications are deduplicated
+        assert!(statuses(15, true).is_empty());
+
+        online.set(1);
+        assert_eq!(statuses(20, false), vec![Status::Resolved]);
+        assert_eq!(statuses(21, true), vec![Status::Resolved]);
+        assert!(statuses(25, true).is_empty());
+    }
+
+    #[tokio::test]
+    async fn notify_every_webhook_despite_failures() {
+        let alerting = Alerting {
+            rules: vec!["gha_runner_online == 0".parse().unwrap()],
+            webhooks: None,
+            http: HttpClient::new().unwrap(),
+            active: Mutex::new(HashMap::new()),
+        };
+        let notification = Notification {
+            status: Status::Firing,
+            index: 0,
+            rule: &alerting.rules[0],
+            labels: Default::default(),
+            value: Some(0.0),
+        };
+        // nothing listens on port 1
+        let webhooks = [
+            (Style::Zulip, "http://127.0.0.1:1/zulip".to_string()),
+            (Style::Slack, "http://127.0.0.1:1/slack".to_string()),
+        ];
+
+        let errors = alerting.notify(Some(&webhooks), &notification).await;
+        assert_eq!(2, errors.len());
+        assert!(errors[1].to_string().contains("slack webhook"));
+        assert!(alerting.notify(None, &notification).await.is_empty());
+    }
+}
@@ -0,0 +1,103 @@
+use crate::collectors::{MetricKind, COLLECTORS, SCHEDULER_METRICS};
+use crate::METRICS_PREFIX;
+use anyhow::{Error, Result};
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// A metric exported by monitorbot, as listed in the metric catalog.
+#[derive(Debug, serde::Serialize)]
+pub struct CatalogEntry {
+    /// Full name of the metric, as exposed on `/metrics`
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: MetricKind,
+    pub help: &'static str,
+    pub labels: &'static [&'static str],
+    /// Name of the collector exporting the metric
+    pub collector: &'static str,
+}
+
+/// Output formats of the metric catalog.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CatalogFormat {
+    Json,
+    Markdown,
+}
+
+impl FromStr for CatalogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "markdown" => Ok(Self::Markdown),
+            _ => anyhow::bail!("unknown format {}, expected json or markdown", s),
+        }
+    }
+}
+
+/// Every metric exported by every collector, as well as by the scheduler.
+pub fn catalog() -> Vec<CatalogEntry> {
+    COLLECTORS
+        .iter()
+        .map(|collector| (collector.name, collector.metrics))
+        .chain(std::iter::once(("scheduler", SCHEDULER_METRICS)))
+        .flat_map(|(collector, metrics)| {
+            metrics.iter().map(move |metric| CatalogEntry {
+                name: format!("{}_{}", METRICS_PREFIX, metric.name),
+                kind: metric.kind,
+                help: metric.help,
+                labels: metric.labels,
+                collector,
+            })
+        })
+        .collect()
+}
+
+pub fn render(format: CatalogFormat) -> Result<String> {
+    let catalog = catalog();
+    match format {
+        CatalogFormat::Json => Ok(serde_json::to_string_pretty(&catalog)?),
+        CatalogFormat::Markdown => Ok(render_markdown(&catalog)),
+    }
+}
+
+fn render_markdown(catalog: &[CatalogEntry]) -> String {
+    let mut out = String::from(
+        "| Metric | Type | Labels | Collector | Description |\n\
+         |--------|------|--------|-----------|-------------|\n",
+    );
+    for entry in catalog {
+        let kind = match entry.kind {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+        };
+        let labels: Vec<String> = entry.labels.iter().map(|l| format!("`{}`", l)).collect();
+        writeln!(
+            out,
+            "| `{}` | {} | {} | {} | {} |",
+            entry.name,
+            kind,
+            labels.join(", "),
+            entry.collector,
+            entry.help.replace('|', "\\|")
+        )
+        .unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::catalog;
+    use std::collections::HashSet;
+
+    #[test]
+    fn catalog_names_are_unique_and_prefixed() {
+        let catalog = catalog();
+        let names: HashSet<&str> = catalog.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(catalog.len(), names.len());
+        assert!(names.contains("monitorbot_gha_runner_online"));
+        assert!(names.iter().all(|name| name.starts_with("monitorbot_")));
+    }
+}
@@ -0,0 +1,190 @@
+use crate::{alerts, collectors};
+use crate::{Config, HttpClient};
+use std::fmt;
+
+/// Outcome of `monitorbot check-config`, printed as a human readable report.
+#[derive(Debug, Default)]
+pub struct Report {
+    sections: Vec<Section>,
+}
+
+#[derive(Debug)]
+struct Section {
+    title: String,
+    checks: Vec<Check>,
+}
+
+#[derive(Debug)]
+struct Check {
+    status: Status,
+    subject: String,
+    message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+impl Report {
+    /// Starts a new section of the report, following checks are added to it.
+    pub fn section(&mut self, title: impl Into<String>) {
+        self.sections.push(Section {
+            title: title.into(),
+            checks: Vec::new(),
+        });
+    }
+
+    pub fn ok(&mut self, subject: impl Into<String>, message: impl Into<String>) {
+        self.push(Status::Ok, subject.into(), message.into());
+    }
+
+    pub fn warning(&mut self, subject: impl Into<String>, message: impl Into<String>) {
+        self.push(Status::Warning, subject.into(), message.into());
+    }
+
+    pub fn error(&mut self, subject: impl Into<String>, message: impl Into<String>) {
+        self.push(Status::Error, subject.into(), message.into());
+    }
+
+    /// Adds an error check describing `err` and its causes.
+    pub fn failure(&mut self, subject: impl Into<String>, err: &anyhow::Error) {
+        // some errors (e.g. reqwest's) already include their source in their
+        // message, so skip causes that were already printed
+        let mut message = String::new();
+        for cause in err.chain().map(|cause| cause.to_string()) {
+            if !message.contains(&cause) {
+                if !message.is_empty() {
+                    message.push_str(": ");
+                }
+                message.push_str(&cause);
+            }
+        }
+        self.error(subject, message);
+    }
+
+    fn push(&mut self, status: Status, subject: String, message: String) {
+        if self.sections.is_empty() {
+            self.section("General");
+        }
+        let section = self.sections.last_mut().unwrap();
+        section.checks.push(Check {
+            status,
+            subject,
+            message,
+        });
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.sections
+            .iter()
+            .flat_map(|section| section.checks.iter())
+            .any(|check| check.status == Status::Error)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for section in self.sections.iter() {
+            writeln!(f, "{}", section.title)?;
+            for check in section.checks.iter() {
+                let status = match check.status {
+                    Status::Ok => "ok",
+                    Status::Warning => "warning",
+                    Status::Error => "error",
+                };
+                writeln!(f, "  {:<8}{}: {}", status, check.subject, check.message)?;
+            }
+            writeln!(f)?;
+        }
+
+        let errors = self
+            .sections
+            .iter()
+            .flat_map(|section| section.checks.iter())
+            .filter(|check| check.status == Status::Error)
+            .count();
+        match errors {
+            0 => writeln!(f, "Configuration is valid"),
+            n => writeln!(f, "Found {} problem(s) with the configuration", n),
+        }
+    }
+}
+
+/// Validates the configuration taken from the environment and verifies that
+/// every configured token and repository can actually be used.
+pub async fn check_config() -> Report {
+    let mut report = Report::default();
+    report.section("Configuration");
+
+    let config = match Config::from_env() {
+        Ok(config) => {
+            report.ok("environment", "configuration loaded");
+            config
+        }
+        Err(e) => {
+            report.failure("environment", &e);
+            return report;
+        }
+    };
+
+    for (name, value) in [
+        (
+            "GH_RATE_LIMIT_STATS_REFRESH",
+            config.gh_rate_limit_stats_cache_refresh,
+        ),
+        ("GHA_RUNNERS_REFRESH", config.gha_runners_cache_refresh),
+        ("HTTP_PROBE_REFRESH", config.http_probe_refresh),
+        ("TLS_REFRESH", config.tls_refresh),
+        ("DNS_REFRESH", config.dns_refresh),
+        ("RUST_CHANNELS_REFRESH", config.rust_channels_refresh),
+        ("CRATES_INDEX_REFRESH", config.crates_index_refresh),
+        ("DOCSRS_REFRESH", config.docsrs_refresh),
+        ("BORS_REFRESH", config.bors_refresh),
+        ("WEBHOOK_REFRESH", config.webhook_refresh),
+        ("GHA_WORKFLOWS_REFRESH", config.gha_workflows_refresh),
+    ] {
+        if value == 0 {
+            report.error(name, "refresh interval must be greater than 0");
+        }
+    }
+
+    let http = match HttpClient::from_config(&config) {
+        Ok(http) => http,
+        Err(e) => {
+            report.failure("http client", &e);
+            return report;
+        }
+    };
+
+    collectors::check_config(&config, &http, &mut report).await;
+    alerts::check_config(&config, &mut report);
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Report;
+
+    #[test]
+    fn report_with_errors() {
+        let mut report = Report::default();
+        report.section("GitHub tokens");
+        report.ok("GITHUB_TOKEN", "authenticated as monitorbot");
+        report.warning("RATE_LIMIT_TOKENS #1", "no scopes");
+        assert!(!report.has_errors());
+
+        report.error("RATE_LIMIT_TOKENS #2", "401 Unauthorized");
+        assert!(report.has_errors());
+        assert_eq!(
+            "GitHub tokens\n  \
+             ok      GITHUB_TOKEN: authenticated as monitorbot\n  \
+             warning RATE_LIMIT_TOKENS #1: no scopes\n  \
+             error   RATE_LIMIT_TOKENS #2: 401 Unauthorized\n\n\
+             Found 1 problem(s) with the configuration\n",
+            report.to_string()
+        );
+    }
+}
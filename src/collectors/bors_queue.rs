@@ -0,0 +1,414 @@
+use super::{default_headers, descs, unix_now, AlertDef, MetricDef, PanelDef, Refresh};
+use crate::check::Report;
+use crate::{Config, HttpClient, Secret, SecretSource};
+use anyhow::{Context, Error, Result};
+use chrono::{DateTime, Utc};
+use futures::future::{join_all, BoxFuture};
+use log::{debug, error};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::IntGauge;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+
+/// Pull requests of a repository known to bors, as
+/// `{"pull_requests": [{"number", "status", "rollup", "started_at"}]}`
+const QUEUE_ENDPOINT: &str = "{bors_url}/api/queue/{owner_repo}";
+// the last commit of the default branch, which bors only pushes to after a successful test
+const LAST_COMMIT_ENDPOINT: &str = "{api_url}/repos/{owner_repo}/commits?per_page=1";
+
+const SCRAPE_SUCCESS: MetricDef = MetricDef::gauge(
+    "bors_scrape_success",
+    "Whether the last requests for the queue and the last merge of the repository succeeded",
+    &["repo"],
+);
+const APPROVED_PRS: MetricDef = MetricDef::gauge(
+    "bors_approved_prs",
+    "Number of approved pull requests waiting to be tested, by rollup mode",
+    &["repo", "rollup"],
+);
+const TESTING_PR: MetricDef = MetricDef::gauge(
+    "bors_testing_pr",
+    "Number of the pull request being tested, 0 if none is",
+    &["repo"],
+);
+const TESTING_DURATION: MetricDef = MetricDef::gauge(
+    "bors_testing_duration_seconds",
+    "Time the pull request being tested has been running for, 0 if none is",
+    &["repo"],
+);
+const LAST_MERGE_AGE: MetricDef = MetricDef::gauge(
+    "bors_last_merge_age_seconds",
+    "Time elapsed since the last commit was merged to the default branch",
+    &["repo"],
+);
+
+pub(crate) const METRICS: &[MetricDef] = &[
+    SCRAPE_SUCCESS,
+    APPROVED_PRS,
+    TESTING_PR,
+    TESTING_DURATION,
+    LAST_MERGE_AGE,
+];
+
+pub(crate) const ALERTS: &[AlertDef] = &[
+    AlertDef {
+        name: "BorsTestingTooLong",
+        expr: "{bors_testing_duration_seconds} > 4 * 3600",
+        for_: "5m",
+        severity: "warning",
+        summary: "bors has been testing the same PR of {{ $labels.repo }} for more than 4 hours",
+    },
+    AlertDef {
+        name: "BorsQueueStalled",
+        expr: "{bors_last_merge_age_seconds} > 6 * 3600 and on (repo) sum by (repo) ({bors_approved_prs}) > 0",
+        for_: "15m",
+        severity: "critical",
+        summary: "Nothing was merged to {{ $labels.repo }} in 6 hours while PRs are approved",
+    },
+];
+
+pub(crate) const PANELS: &[PanelDef] = &[
+    PanelDef {
+        title: "Approved PRs",
+        expr: "sum by (repo) ({bors_approved_prs})",
+        legend: "{{repo}}",
+        unit: "short",
+    },
+    PanelDef {
+        title: "Time since the last merge",
+        expr: "{bors_last_merge_age_seconds}",
+        legend: "{{repo}}",
+        unit: "s",
+    },
+];
+
+// how an approved pull request can be included in rollups, as set with `@bors rollup=...`
+const ROLLUP_MODES: [&str; 4] = ["always", "maybe", "iffy", "never"];
+
+#[derive(Debug, serde::Deserialize)]
+struct Queue {
+    pull_requests: Vec<PullRequest>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct PullRequest {
+    number: i64,
+    // `approved` while waiting and `pending` while being tested
+    status: String,
+    // not set on pull requests approved without a rollup mode, which default to `maybe`
+    rollup: Option<String>,
+    started_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Commit {
+    commit: CommitDetails,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CommitDetails {
+    committer: Signature,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Signature {
+    date: DateTime<Utc>,
+}
+
+/// State of the queue of a repository.
+#[derive(Debug, PartialEq, Eq)]
+struct QueueStats {
+    // one per rollup mode, in order
+    approved: [i64; 4],
+    testing: Option<(i64, i64)>,
+    last_merge: i64,
+}
+
+impl QueueStats {
+    fn new(queue: &Queue, last_merge: i64) -> Self {
+        let mut approved = [0; 4];
+        let mut testing = None;
+        for pr in queue.pull_requests.iter() {
+            match pr.status.as_str() {
+                "approved" => {
+                    let rollup = pr.rollup.as_deref().unwrap_or("maybe");
+                    // unknown modes are counted as the default one
+                    let mode = ROLLUP_MODES.iter().position(|m| *m == rollup).unwrap_or(1);
+                    approved[mode] += 1;
+                }
+                "pending" => {
+                    let started = pr.started_at.map_or(0, |started| started.timestamp());
+                    testing = Some((pr.number, started));
+                }
+                _ => {}
+            }
+        }
+        Self {
+            approved,
+            testing,
+            last_merge,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BorsQueue {
+    token: SecretSource,
+    repos: Arc<Vec<RepoMetrics>>,
+    bors_url: String,
+    api_url: String,
+    descs: Vec<Desc>,
+    http: HttpClient,
+}
+
+struct RepoMetrics {
+    repo: String,
+    scrape_success: IntGauge,
+    // one per rollup mode, in order
+    approved: Vec<IntGauge>,
+    testing_pr: IntGauge,
+    testing_duration: IntGauge,
+    last_merge_age: IntGauge,
+    // unix times the durations are computed from on every scrape, 0 if unknown
+    testing_since: AtomicI64,
+    last_merge: AtomicI64,
+}
+
+impl RepoMetrics {
+    fn new(repo: String) -> Self {
+        let opts = |def: MetricDef| def.opts().const_label("repo", &repo);
+        Self {
+            scrape_success: IntGauge::with_opts(opts(SCRAPE_SUCCESS)).unwrap(),
+            approved: ROLLUP_MODES
+                .iter()
+                .map(|mode| {
+                    IntGauge::with_opts(opts(APPROVED_PRS).const_label("rollup", *mode)).unwrap()
+                })
+                .collect(),
+            testing_pr: IntGauge::with_opts(opts(TESTING_PR)).unwrap(),
+            testing_duration: IntGauge::with_opts(opts(TESTING_DURATION)).unwrap(),
+            last_merge_age: IntGauge::with_opts(opts(LAST_MERGE_AGE)).unwrap(),
+            testing_since: AtomicI64::new(0),
+            last_merge: AtomicI64::new(0),
+            repo,
+        }
+    }
+
+    fn update(&self, stats: &QueueStats) {
+        for (gauge, count) in self.approved.iter().zip(stats.approved.iter()) {
+            gauge.set(*count);
+        }
+        let (pr, since) = stats.testing.unwrap_or((0, 0));
+        self.testing_pr.set(pr);
+        self.testing_since.store(since, Ordering::SeqCst);
+        self.last_merge.store(stats.last_merge, Ordering::SeqCst);
+    }
+}
+
+fn parse_repos(repos: &str) -> Vec<String> {
+    repos
+        .split(',')
+        .map(str::trim)
+        .filter(|repo| !repo.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+impl BorsQueue {
+    pub fn new(config: &Config, http: HttpClient) -> Result<Self> {
+        let repos = parse_repos(&config.bors_repos)
+            .into_iter()
+            .map(RepoMetrics::new)
+            .collect();
+
+        Ok(Self {
+            token: config.github_token.clone(),
+            repos: Arc::new(repos),
+            bors_url: config.bors_url.clone(),
+            api_url: config.github_api_url.clone(),
+            descs: descs(METRICS),
+            http,
+        })
+    }
+
+    async fn update_stats(&self) -> Result<(), Error> {
+        debug!("Updating bors queue stats");
+
+        let token = self.token.get()?;
+        let results = join_all(self.repos.iter().map(|metrics| {
+            fetch(
+                &self.http,
+                &self.bors_url,
+                &self.api_url,
+                &token,
+                &metrics.repo,
+            )
+        }))
+        .await;
+
+        let mut failed = 0;
+        for (metrics, result) in self.repos.iter().zip(results) {
+            match result {
+                Ok(stats) => {
+                    metrics.update(&stats);
+                    metrics.scrape_success.set(1);
+                }
+                Err(e) => {
+                    error!(
+                        "Unable to update the bors queue of {}: {:?}",
+                        metrics.repo, e
+                    );
+                    metrics.scrape_success.set(0);
+                    failed += 1;
+                }
+            }
+        }
+
+        if failed > 0 {
+            anyhow::bail!(
+                "failed to update the bors queue of {} of {} repositories",
+                failed,
+                self.repos.len()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+async fn fetch(
+    http: &HttpClient,
+    bors_url: &str,
+    api_url: &str,
+    token: &Secret,
+    repo: &str,
+) -> Result<QueueStats> {
+    let url = QUEUE_ENDPOINT
+        .replace("{bors_url}", bors_url)
+        .replace("{owner_repo}", repo);
+    let queue = http
+        .send(http.get(&url))
+        .await?
+        .error_for_status()?
+        .json::<Queue>()
+        .await
+        .context("unable to fetch the queue")?;
+
+    let url = LAST_COMMIT_ENDPOINT
+        .replace("{api_url}", api_url)
+        .replace("{owner_repo}", repo);
+    let commits = http
+        .send(http.get(&url).headers(default_headers(token)))
+        .await?
+        .error_for_status()?
+        .json::<Vec<Commit>>()
+        .await
+        .context("unable to fetch the last commit of the default branch")?;
+    let last_merge = commits
+        .first()
+        .map_or(0, |commit| commit.commit.committer.date.timestamp());
+
+    Ok(QueueStats::new(&queue, last_merge))
+}
+
+impl Refresh for BorsQueue {
+    fn refresh(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(self.update_stats())
+    }
+}
+
+impl Collector for BorsQueue {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let now = unix_now();
+        let age = |timestamp: i64| match timestamp {
+            0 => 0,
+            timestamp => (now - timestamp).max(0),
+        };
+
+        let mut metrics = Vec::new();
+        for repo in self.repos.iter() {
+            repo.testing_duration
+                .set(age(repo.testing_since.load(Ordering::SeqCst)));
+            let last_merge = repo.last_merge.load(Ordering::SeqCst);
+            repo.last_merge_age.set(age(last_merge));
+
+            metrics.extend(repo.scrape_success.collect());
+            for approved in repo.approved.iter() {
+                metrics.extend(approved.collect());
+            }
+            metrics.extend(repo.testing_pr.collect());
+            metrics.extend(repo.testing_duration.collect());
+            // there's no last merge to report until the repository was fetched once
+            if last_merge > 0 {
+                metrics.extend(repo.last_merge_age.collect());
+            }
+        }
+        metrics
+    }
+}
+
+pub(crate) async fn check_config(config: &Config, http: &HttpClient, report: &mut Report) {
+    report.section("bors queue (BORS_URL, BORS_REPOS, GITHUB_TOKEN)");
+
+    let repos = parse_repos(&config.bors_repos);
+    if repos.is_empty() {
+        report.ok("BORS_REPOS", "no repositories, collector disabled");
+        return;
+    }
+
+    let token = match config.github_token.get() {
+        Ok(token) => token,
+        Err(e) => {
+            report.failure("GITHUB_TOKEN", &e);
+            return;
+        }
+    };
+    for repo in repos.iter() {
+        if repo.split('/').count() != 2 || repo.split('/').any(str::is_empty) {
+            report.error(repo, "expected a repository in the owner/repo format");
+            continue;
+        }
+        match fetch(http, &config.bors_url, &config.github_api_url, &token, repo).await {
+            Ok(stats) => report.ok(
+                repo,
+                format!("{} approved PR(s)", stats.approved.iter().sum::<i64>()),
+            ),
+            Err(e) => report.failure(repo, &e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Queue, QueueStats};
+    use serde_json::json;
+
+    #[test]
+    fn queue_stats_by_rollup_mode() {
+        let queue: Queue = serde_json::from_value(json!({
+            "pull_requests": [
+                { "number": 1, "status": "approved", "rollup": "always", "started_at": null },
+                { "number": 2, "status": "approved", "rollup": null, "started_at": null },
+                { "number": 3, "status": "approved", "rollup": "never", "started_at": null },
+                { "number": 4, "status": "pending", "rollup": "never", "started_at": "2024-06-21T12:00:00Z" },
+                { "number": 5, "status": "failure", "rollup": "maybe", "started_at": null },
+                { "number": 6, "status": "", "rollup": null, "started_at": null },
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            QueueStats {
+                approved: [1, 1, 0, 1],
+                testing: Some((4, 1_718_971_200)),
+                last_merge: 42,
+            },
+            QueueStats::new(&queue, 42)
+        );
+    }
+}
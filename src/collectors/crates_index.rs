@@ -0,0 +1,527 @@
+use super::{descs, unix_now, AlertDef, MetricDef, PanelDef, Refresh};
+use crate::check::Report;
+use crate::{Config, HttpClient};
+use anyhow::{Context, Error, Result};
+use chrono::{DateTime, Utc};
+use futures::future::{join_all, BoxFuture};
+use log::{debug, warn};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{Gauge, IntCounter, IntGauge};
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+const NEWEST_VERSION_AGE: MetricDef = MetricDef::gauge(
+    "crates_index_newest_version_age_seconds",
+    "Time elapsed since the newest version found in the index was published",
+    &["index"],
+);
+const LAG: MetricDef = MetricDef::gauge(
+    "crates_index_lag_seconds",
+    "Time between the newest version published on crates.io and the newest version found in the index",
+    &["index"],
+);
+const MISSING_VERSIONS: MetricDef = MetricDef::gauge(
+    "crates_index_missing_versions",
+    "Number of versions in the recently updated list of crates.io that aren't in the index yet",
+    &["index"],
+);
+const FETCH_DURATION: MetricDef = MetricDef::gauge(
+    "crates_index_fetch_duration_seconds",
+    "Time taken to fetch the index in the last refresh",
+    &["index"],
+);
+const FETCH_ERRORS: MetricDef = MetricDef::counter(
+    "crates_index_fetch_errors_total",
+    "Number of failed requests to the index",
+    &["index"],
+);
+
+pub(crate) const METRICS: &[MetricDef] = &[
+    NEWEST_VERSION_AGE,
+    LAG,
+    MISSING_VERSIONS,
+    FETCH_DURATION,
+    FETCH_ERRORS,
+];
+
+pub(crate) const ALERTS: &[AlertDef] = &[
+    AlertDef {
+        name: "CratesIndexStuck",
+        expr: "{crates_index_lag_seconds} > 900",
+        for_: "10m",
+        severity: "critical",
+        summary: "The {{ $labels.index }} crates.io index is more than 15 minutes behind crates.io",
+    },
+    AlertDef {
+        name: "CratesIndexFetchFailing",
+        expr: "rate({crates_index_fetch_errors_total}[10m]) > 0",
+        for_: "30m",
+        severity: "warning",
+        summary: "Requests to the {{ $labels.index }} crates.io index are failing",
+    },
+];
+
+pub(crate) const PANELS: &[PanelDef] = &[
+    PanelDef {
+        title: "Index lag",
+        expr: "{crates_index_lag_seconds}",
+        legend: "{{index}}",
+        unit: "s",
+    },
+    PanelDef {
+        title: "Index fetch latency",
+        expr: "{crates_index_fetch_duration_seconds}",
+        legend: "{{index}}",
+        unit: "s",
+    },
+];
+
+/// A version from the recently updated lists of the crates.io summary.
+#[derive(Debug, Clone, serde::Deserialize)]
+struct RecentVersion {
+    name: String,
+    #[serde(rename = "newest_version")]
+    version: String,
+    #[serde(rename = "updated_at")]
+    published: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Summary {
+    just_updated: Vec<RecentVersion>,
+    new_crates: Vec<RecentVersion>,
+}
+
+/// Entry of a crate's file in the sparse index, one per version.
+#[derive(Debug, serde::Deserialize)]
+struct IndexEntry {
+    vers: String,
+}
+
+enum Index {
+    // base url of a sparse index, e.g. https://index.crates.io
+    Sparse(String),
+    // url of a git index remote, whose head is tracked to know when it last changed
+    Git(String, Mutex<Option<GitHead>>),
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct GitHead {
+    commit: String,
+    // unix time the commit was first seen as the head, unknown when it already
+    // was the head the first time the index was fetched
+    since: Option<i64>,
+}
+
+// records the current head of the git index, returning the time it became
+// the head if it's known
+fn observe_head(head: &mut Option<GitHead>, commit: String, now: i64) -> Option<i64> {
+    match head {
+        Some(known) if known.commit == commit => {}
+        _ => {
+            debug!("The head of the git index is now {}", commit);
+            let since = head.as_ref().map(|_| now);
+            *head = Some(GitHead { commit, since });
+        }
+    }
+    head.as_ref().and_then(|head| head.since)
+}
+
+impl Index {
+    fn label(&self) -> &'static str {
+        match self {
+            Index::Sparse(_) => "sparse",
+            Index::Git(..) => "git",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CratesIndex {
+    indexes: Arc<Vec<IndexMetrics>>,
+    api_url: String,
+    descs: Vec<Desc>,
+    http: HttpClient,
+}
+
+struct IndexMetrics {
+    index: Index,
+    // unix time the newest version found in the index was published, 0 until known
+    newest_version: AtomicI64,
+    newest_version_age: IntGauge,
+    lag: IntGauge,
+    missing_versions: IntGauge,
+    fetch_duration: Gauge,
+    fetch_errors: IntCounter,
+}
+
+impl CratesIndex {
+    pub fn new(config: &Config, http: HttpClient) -> Result<Self> {
+        let mut indexes = Vec::new();
+        if !config.crates_index_url.is_empty() {
+            indexes.push(Index::Sparse(config.crates_index_url.clone()));
+        }
+        if !config.crates_index_git_url.is_empty() {
+            indexes.push(Index::Git(
+                config.crates_index_git_url.clone(),
+                Mutex::new(None),
+            ));
+        }
+
+        let indexes = indexes
+            .into_iter()
+            .map(|index| {
+                let opts = |def: MetricDef| def.opts().const_label("index", index.label());
+                IndexMetrics {
+                    newest_version: AtomicI64::new(0),
+                    newest_version_age: IntGauge::with_opts(opts(NEWEST_VERSION_AGE)).unwrap(),
+                    lag: IntGauge::with_opts(opts(LAG)).unwrap(),
+                    missing_versions: IntGauge::with_opts(opts(MISSING_VERSIONS)).unwrap(),
+                    fetch_duration: Gauge::with_opts(opts(FETCH_DURATION)).unwrap(),
+                    fetch_errors: IntCounter::with_opts(opts(FETCH_ERRORS)).unwrap(),
+                    index,
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            indexes: Arc::new(indexes),
+            api_url: config.crates_io_api_url.clone(),
+            descs: descs(METRICS),
+            http,
+        })
+    }
+
+    async fn update_stats(&self) -> Result<(), Error> {
+        debug!("Updating crates.io index freshness");
+
+        let recent = recent_versions(&self.http, &self.api_url)
+            .await
+            .context("Unable to fetch the recently updated crates")?;
+        if recent.is_empty() {
+            warn!("crates.io returned no recently updated crates");
+            return Ok(());
+        }
+
+        for metrics in self.indexes.iter() {
+            let start = Instant::now();
+            let present = match &metrics.index {
+                Index::Sparse(url) => sparse_versions(&self.http, url, &recent).await.map(Some),
+                Index::Git(url, head) => git_versions(&self.http, url, head, &recent).await,
+            };
+            metrics.fetch_duration.set(start.elapsed().as_secs_f64());
+
+            let present = match present {
+                Ok(Some(present)) => present,
+                Ok(None) => {
+                    debug!("Waiting for the head of the git index to change");
+                    continue;
+                }
+                Err(e) => {
+                    warn!(
+                        "Unable to fetch the {} index: {:?}",
+                        metrics.index.label(),
+                        e
+                    );
+                    metrics.fetch_errors.inc();
+                    continue;
+                }
+            };
+            metrics.update(&recent, &present);
+        }
+
+        Ok(())
+    }
+}
+
+impl IndexMetrics {
+    fn update(&self, recent: &[RecentVersion], present: &[bool]) {
+        let newest_published = recent.iter().map(|v| v.published.timestamp()).max();
+        let newest_present = recent
+            .iter()
+            .zip(present)
+            .filter(|(_, present)| **present)
+            .map(|(version, _)| version.published.timestamp())
+            .max();
+        // when none of the recent versions are in the index, its newest
+        // version is at least older than all of them
+        let newest_version = match newest_present {
+            Some(newest) => newest,
+            None => recent
+                .iter()
+                .map(|v| v.published.timestamp())
+                .min()
+                .unwrap_or(0),
+        };
+
+        self.newest_version.store(newest_version, Ordering::SeqCst);
+        self.lag
+            .set(newest_published.map_or(0, |newest| newest - newest_version));
+        self.missing_versions
+            .set(present.iter().filter(|present| !**present).count() as i64);
+    }
+}
+
+async fn recent_versions(http: &HttpClient, api_url: &str) -> Result<Vec<RecentVersion>> {
+    let summary = http
+        .send(http.get(&format!("{}/summary", api_url)))
+        .await?
+        .error_for_status()?
+        .json::<Summary>()
+        .await?;
+    Ok(summary
+        .just_updated
+        .into_iter()
+        .chain(summary.new_crates)
+        .collect())
+}
+
+// path of a crate's file in the sparse index, e.g. `se/rd/serde`
+fn sparse_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => format!("1/{}", name),
+        2 => format!("2/{}", name),
+        3 => format!("3/{}/{}", &name[..1], name),
+        _ => format!("{}/{}/{}", &name[..2], &name[2..4], name),
+    }
+}
+
+// whether every recent version is in the sparse index
+async fn sparse_versions(
+    http: &HttpClient,
+    url: &str,
+    recent: &[RecentVersion],
+) -> Result<Vec<bool>> {
+    let files = join_all(recent.iter().map(|version| async move {
+        let url = format!("{}/{}", url, sparse_path(&version.name));
+        let response = http.send(http.get(&url)).await?;
+        // crates published for the first time aren't in the index yet
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let file = response.error_for_status()?.text().await?;
+        Ok::<_, Error>(Some(file))
+    }))
+    .await;
+
+    recent
+        .iter()
+        .zip(files)
+        .map(|(version, file)| {
+            let file = match file? {
+                Some(file) => file,
+                None => return Ok(false),
+            };
+            let versions = file
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| Ok(serde_json::from_str::<IndexEntry>(line)?.vers))
+                .collect::<Result<HashSet<_>>>()
+                .with_context(|| format!("invalid index file for {}", version.name))?;
+            Ok(versions.contains(&version.version))
+        })
+        .collect()
+}
+
+// the git index can't be queried for a single crate without cloning it, so
+// versions published before its head last changed are assumed to be in it,
+// nothing is known until the head was seen changing
+async fn git_versions(
+    http: &HttpClient,
+    url: &str,
+    head: &Mutex<Option<GitHead>>,
+    recent: &[RecentVersion],
+) -> Result<Option<Vec<bool>>> {
+    let commit = advertised_head(http, url).await?;
+    let now = unix_now();
+
+    let since = match observe_head(&mut head.lock().unwrap(), commit, now) {
+        Some(since) => since,
+        None => return Ok(None),
+    };
+    Ok(Some(
+        recent
+            .iter()
+            .map(|version| version.published.timestamp() <= since)
+            .collect(),
+    ))
+}
+
+// fetches the commit the remote's HEAD points to, from the ref advertisement
+// of the git smart http protocol
+async fn advertised_head(http: &HttpClient, url: &str) -> Result<String> {
+    let url = format!("{}/info/refs?service=git-upload-pack", url);
+    let refs = http
+        .send(http.get(&url))
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    parse_advertised_head(&refs).with_context(|| format!("invalid ref advertisement of {}", url))
+}
+
+fn parse_advertised_head(mut refs: &[u8]) -> Result<String> {
+    // every line is prefixed with its length in hex, including the prefix
+    while refs.len() >= 4 {
+        let len = usize::from_str_radix(std::str::from_utf8(&refs[..4])?, 16)?;
+        if len == 0 {
+            // flush packet
+            refs = &refs[4..];
+            continue;
+        }
+        anyhow::ensure!((4..=refs.len()).contains(&len), "truncated line");
+        let line = String::from_utf8_lossy(&refs[4..len]);
+        refs = &refs[len..];
+
+        // the first ref is followed by the capabilities of the server
+        let line = line.split('\0').next().unwrap_or_default().trim_end();
+        if let Some((commit, "HEAD")) = line.split_once(' ') {
+            return Ok(commit.to_string());
+        }
+    }
+    anyhow::bail!("the remote doesn't advertise a HEAD")
+}
+
+impl Refresh for CratesIndex {
+    fn refresh(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(self.update_stats())
+    }
+
+    // only the head of the git index is worth persisting, as the time it last
+    // changed can't be fetched again
+    fn snapshot(&self) -> Result<Option<Value>, Error> {
+        for metrics in self.indexes.iter() {
+            if let Index::Git(_, head) = &metrics.index {
+                return Ok(Some(serde_json::to_value(&*head.lock().unwrap())?));
+            }
+        }
+        Ok(None)
+    }
+
+    fn restore(&self, snapshot: Value) -> Result<(), Error> {
+        let snapshot: Option<GitHead> = serde_json::from_value(snapshot)?;
+        for metrics in self.indexes.iter() {
+            if let Index::Git(_, head) = &metrics.index {
+                *head.lock().unwrap() = snapshot.clone();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Collector for CratesIndex {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let now = unix_now();
+
+        let mut metrics = Vec::new();
+        for index in self.indexes.iter() {
+            // nothing is known about the index until it was fetched once
+            let newest_version = index.newest_version.load(Ordering::SeqCst);
+            if newest_version > 0 {
+                index.newest_version_age.set((now - newest_version).max(0));
+                metrics.extend(index.newest_version_age.collect());
+                metrics.extend(index.lag.collect());
+                metrics.extend(index.missing_versions.collect());
+            }
+            metrics.extend(index.fetch_duration.collect());
+            metrics.extend(index.fetch_errors.collect());
+        }
+        metrics
+    }
+}
+
+pub(crate) async fn check_config(config: &Config, http: &HttpClient, report: &mut Report) {
+    report.section("crates.io index (CRATES_INDEX_URL, CRATES_INDEX_GIT_URL)");
+
+    if config.crates_index_url.is_empty() && config.crates_index_git_url.is_empty() {
+        report.ok("CRATES_INDEX_URL", "no index, collector disabled");
+        return;
+    }
+
+    let recent = match recent_versions(http, &config.crates_io_api_url).await {
+        Ok(recent) => {
+            report.ok(
+                "CRATES_IO_API_URL",
+                format!("{} recently updated crates", recent.len()),
+            );
+            recent
+        }
+        Err(e) => {
+            report.failure("CRATES_IO_API_URL", &e);
+            return;
+        }
+    };
+
+    if !config.crates_index_url.is_empty() {
+        match sparse_versions(http, &config.crates_index_url, &recent).await {
+            Ok(present) => {
+                let missing = present.iter().filter(|present| !**present).count();
+                // a lagging index is worth knowing about, but isn't a configuration error
+                if missing == 0 {
+                    report.ok(&config.crates_index_url, "every recent version is indexed");
+                } else {
+                    report.warning(
+                        &config.crates_index_url,
+                        format!("{} recent versions aren't indexed yet", missing),
+                    );
+                }
+            }
+            Err(e) => report.failure(&config.crates_index_url, &e),
+        }
+    }
+
+    if !config.crates_index_git_url.is_empty() {
+        match advertised_head(http, &config.crates_index_git_url).await {
+            Ok(commit) => report.ok(&config.crates_index_git_url, format!("head at {}", commit)),
+            Err(e) => report.failure(&config.crates_index_git_url, &e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{observe_head, parse_advertised_head, sparse_path};
+
+    #[test]
+    fn sparse_index_paths() {
+        assert_eq!("1/a", sparse_path("a"));
+        assert_eq!("2/cc", sparse_path("cc"));
+        assert_eq!("3/s/syn", sparse_path("syn"));
+        assert_eq!("se/rd/serde", sparse_path("serde"));
+        assert_eq!("to/ki/tokio", sparse_path("Tokio"));
+    }
+
+    #[test]
+    fn git_head_changes() {
+        let mut head = None;
+        assert_eq!(None, observe_head(&mut head, "a".into(), 100));
+        assert_eq!(None, observe_head(&mut head, "a".into(), 200));
+        assert_eq!(Some(300), observe_head(&mut head, "b".into(), 300));
+        assert_eq!(Some(300), observe_head(&mut head, "b".into(), 400));
+    }
+
+    #[test]
+    fn parse_ref_advertisements() {
+        let refs = "001e# service=git-upload-pack\n\
+                    0000\
+                    004a6f2e8b6d4a4c0b2e6c1e2ad2d5f8b5d0c7e3a9f1 HEAD\0multi_ack side-band-64k\n\
+                    003f6f2e8b6d4a4c0b2e6c1e2ad2d5f8b5d0c7e3a9f1 refs/heads/master\n\
+                    0000";
+        assert_eq!(
+            "6f2e8b6d4a4c0b2e6c1e2ad2d5f8b5d0c7e3a9f1",
+            parse_advertised_head(refs.as_bytes()).unwrap()
+        );
+        assert!(parse_advertised_head(b"0000").is_err());
+        assert!(parse_advertised_head(b"00ff truncated").is_err());
+    }
+}
@@ -0,0 +1,499 @@
+use super::{descs, AlertDef, MetricDef, PanelDef, Refresh};
+use crate::check::Report;
+use crate::Config;
+use anyhow::{Context, Error, Result};
+use futures::future::{join_all, BoxFuture};
+use hickory_proto::op::{Message, MessageType, Query, ResponseCode};
+use hickory_proto::rr::{Name, RecordType};
+use log::{debug, warn};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{Gauge, IntCounter, IntGauge};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const SUCCESS: MetricDef = MetricDef::gauge(
+    "dns_resolution_success",
+    "Whether the record resolved to at least one answer in the last query",
+    &["name", "type"],
+);
+const DURATION: MetricDef = MetricDef::gauge(
+    "dns_resolution_duration_seconds",
+    "Time taken by the resolver to answer the last query, or until the query failed",
+    &["name", "type"],
+);
+const MATCH: MetricDef = MetricDef::gauge(
+    "dns_answers_match",
+    "Whether every answer of the last query is one of the expected values",
+    &["name", "type"],
+);
+const MISMATCHES: MetricDef = MetricDef::counter(
+    "dns_answer_mismatches_total",
+    "Number of queries answered with values that aren't expected",
+    &["name", "type"],
+);
+
+pub(crate) const METRICS: &[MetricDef] = &[SUCCESS, DURATION, MATCH, MISMATCHES];
+
+pub(crate) const ALERTS: &[AlertDef] = &[
+    AlertDef {
+        name: "DnsResolutionFailing",
+        expr: "{dns_resolution_success} == 0",
+        for_: "10m",
+        severity: "critical",
+        summary: "The {{ $labels.type }} record of {{ $labels.name }} doesn't resolve",
+    },
+    AlertDef {
+        name: "DnsAnswersChanged",
+        expr: "{dns_answers_match} == 0",
+        for_: "5m",
+        severity: "critical",
+        summary:
+            "The {{ $labels.type }} record of {{ $labels.name }} resolves to unexpected values",
+    },
+];
+
+pub(crate) const PANELS: &[PanelDef] = &[
+    PanelDef {
+        title: "DNS resolution latency",
+        expr: "{dns_resolution_duration_seconds}",
+        legend: "{{name}} {{type}}",
+        unit: "s",
+    },
+    PanelDef {
+        title: "Unexpected DNS answers",
+        expr: "increase({dns_answer_mismatches_total}[1h])",
+        legend: "{{name}} {{type}}",
+        unit: "short",
+    },
+];
+
+/// A record to resolve along with its expected values, parsed from e.g.
+/// `crates.io A=13.32.1.1|13.32.1.2` or `docs.rs AAAA`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    name: String,
+    record_type: RecordType,
+    // empty if any answer is fine
+    expected: Vec<String>,
+}
+
+impl FromStr for Record {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let name = parts.next().context("empty DNS record")?;
+        let spec = parts
+            .next()
+            .with_context(|| format!("missing record type for {}", name))?;
+        anyhow::ensure!(
+            parts.next().is_none(),
+            "expected `name TYPE[=value|...]`, got {}",
+            s
+        );
+
+        let (record_type, expected) = match spec.split_once('=') {
+            Some((record_type, expected)) => {
+                (record_type, expected.split('|').map(normalize).collect())
+            }
+            None => (spec, Vec::new()),
+        };
+        let record_type = RecordType::from_str(&record_type.to_uppercase())
+            .with_context(|| format!("invalid record type {} for {}", record_type, name))?;
+
+        Ok(Self {
+            name: name.to_string(),
+            record_type,
+            expected,
+        })
+    }
+}
+
+fn parse_records(records: &str) -> Result<Vec<Record>> {
+    records
+        .split(',')
+        .filter(|record| !record.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+// answers are compared case insensitively and without the trailing dot of names
+fn normalize(value: &str) -> String {
+    value.trim().trim_end_matches('.').to_lowercase()
+}
+
+#[derive(Clone)]
+pub struct DnsRecords {
+    records: Arc<Vec<RecordMetrics>>,
+    resolver: SocketAddr,
+    timeout: Duration,
+    descs: Vec<Desc>,
+}
+
+struct RecordMetrics {
+    record: Record,
+    success: IntGauge,
+    duration: Gauge,
+    // only exported for records with expected values
+    matches: Option<IntGauge>,
+    mismatches: IntCounter,
+}
+
+impl DnsRecords {
+    pub fn new(config: &Config) -> Result<Self> {
+        let records = parse_records(&config.dns_records)?
+            .into_iter()
+            .map(|record| {
+                let opts = |def: MetricDef| {
+                    def.opts()
+                        .const_label("name", &record.name)
+                        .const_label("type", record.record_type.to_string())
+                };
+                RecordMetrics {
+                    success: IntGauge::with_opts(opts(SUCCESS)).unwrap(),
+                    duration: Gauge::with_opts(opts(DURATION)).unwrap(),
+                    matches: match record.expected.is_empty() {
+                        true => None,
+                        false => Some(IntGauge::with_opts(opts(MATCH)).unwrap()),
+                    },
+                    mismatches: IntCounter::with_opts(opts(MISMATCHES)).unwrap(),
+                    record,
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            records: Arc::new(records),
+            resolver: config.dns_resolver,
+            timeout: Duration::from_secs(config.dns_timeout),
+            descs: descs(METRICS),
+        })
+    }
+
+    async fn update_stats(&self) -> Result<(), Error> {
+        debug!("Resolving {} DNS records", self.records.len());
+
+        let results = join_all(self.records.iter().map(|r| async move {
+            let start = Instant::now();
+            let result = resolve(self.resolver, r.record.clone(), self.timeout).await;
+            (result, start.elapsed())
+        }))
+        .await;
+
+        for (metrics, (result, elapsed)) in self.records.iter().zip(results) {
+            let record = &metrics.record;
+            let (answers, duration) = match result {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    warn!(
+                        "Unable to resolve the {} record of {}: {:?}",
+                        record.record_type, record.name, e
+                    );
+                    metrics.success.set(0);
+                    metrics.duration.set(elapsed.as_secs_f64());
+                    if let Some(matches) = &metrics.matches {
+                        matches.set(0);
+                    }
+                    continue;
+                }
+            };
+
+            metrics.success.set(if answers.is_empty() { 0 } else { 1 });
+            metrics.duration.set(duration.as_secs_f64());
+            if let Some(matches) = &metrics.matches {
+                let matching = answers_match(record, &answers);
+                matches.set(if matching { 1 } else { 0 });
+                if !matching {
+                    warn!(
+                        "Unexpected answers for the {} record of {}: {}",
+                        record.record_type,
+                        record.name,
+                        answers.join(", ")
+                    );
+                    metrics.mismatches.inc();
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// every answer must be expected, but not every expected value has to be
+// returned, as load balanced records only return some of their addresses
+fn answers_match(record: &Record, answers: &[String]) -> bool {
+    !answers.is_empty()
+        && answers
+            .iter()
+            .all(|answer| record.expected.contains(answer))
+}
+
+async fn resolve(
+    resolver: SocketAddr,
+    record: Record,
+    timeout: Duration,
+) -> Result<(Vec<String>, Duration)> {
+    tokio::task::spawn_blocking(move || query(resolver, &record, timeout)).await?
+}
+
+// sends a single recursive query over UDP, retried over TCP if the answer
+// was truncated, returning the normalized answers of the requested type
+// along with the time the resolver took to answer
+fn query(
+    resolver: SocketAddr,
+    record: &Record,
+    timeout: Duration,
+) -> Result<(Vec<String>, Duration)> {
+    let id = rand::random();
+    let mut request = Message::new();
+    request
+        .set_id(id)
+        .set_message_type(MessageType::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(
+            Name::from_ascii(&record.name)?,
+            record.record_type,
+        ));
+
+    let request = request.to_vec()?;
+
+    let start = Instant::now();
+    let mut response = exchange_udp(resolver, &request, id, timeout)?;
+    if response.truncated() {
+        debug!("Truncated answer for {}, retrying over TCP", record.name);
+        response = exchange_tcp(resolver, &request, id, timeout)?;
+    }
+    let duration = start.elapsed();
+
+    anyhow::ensure!(
+        response.response_code() == ResponseCode::NoError,
+        "the resolver answered {}",
+        response.response_code()
+    );
+    let answers = response
+        .answers()
+        .iter()
+        .filter(|answer| answer.record_type() == record.record_type)
+        .filter_map(|answer| answer.data())
+        .map(|data| normalize(&data.to_string()))
+        .collect();
+    Ok((answers, duration))
+}
+
+fn exchange_udp(
+    resolver: SocketAddr,
+    request: &[u8],
+    id: u16,
+    timeout: Duration,
+) -> Result<Message> {
+    let bind: SocketAddr = match resolver {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(resolver)?;
+
+    socket.send(request)?;
+    let mut buffer = [0; 4096];
+    loop {
+        let len = socket
+            .recv(&mut buffer)
+            .with_context(|| format!("no answer from {}", resolver))?;
+        let response = Message::from_vec(&buffer[..len])?;
+        // ignore stray answers to previous queries
+        if response.id() == id {
+            return Ok(response);
+        }
+    }
+}
+
+// messages over TCP are prefixed by their length as two bytes
+fn exchange_tcp(
+    resolver: SocketAddr,
+    request: &[u8],
+    id: u16,
+    timeout: Duration,
+) -> Result<Message> {
+    let mut stream = TcpStream::connect_timeout(&resolver, timeout)
+        .with_context(|| format!("unable to connect to {} over TCP", resolver))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut framed = (request.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(request);
+    stream.write_all(&framed)?;
+
+    let mut len = [0; 2];
+    stream
+        .read_exact(&mut len)
+        .with_context(|| format!("no answer from {} over TCP", resolver))?;
+    let mut buffer = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buffer)?;
+    let response = Message::from_vec(&buffer)?;
+    anyhow::ensure!(
+        response.id() == id,
+        "{} answered another query over TCP",
+        resolver
+    );
+    anyhow::ensure!(
+        !response.truncated(),
+        "{} truncated its answer over TCP",
+        resolver
+    );
+    Ok(response)
+}
+
+impl Refresh for DnsRecords {
+    fn refresh(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(self.update_stats())
+    }
+}
+
+impl Collector for DnsRecords {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut metrics = Vec::new();
+        for record in self.records.iter() {
+            metrics.extend(record.success.collect());
+            metrics.extend(record.duration.collect());
+            if let Some(matches) = &record.matches {
+                metrics.extend(matches.collect());
+            }
+            metrics.extend(record.mismatches.collect());
+        }
+        metrics
+    }
+}
+
+pub(crate) async fn check_config(config: &Config, report: &mut Report) {
+    report.section("DNS records (DNS_RECORDS, DNS_RESOLVER)");
+
+    let records = match parse_records(&config.dns_records) {
+        Ok(records) if records.is_empty() => {
+            report.ok("DNS_RECORDS", "no records, collector disabled");
+            return;
+        }
+        Ok(records) => records,
+        Err(e) => {
+            report.failure("DNS_RECORDS", &e);
+            return;
+        }
+    };
+
+    let timeout = Duration::from_secs(config.dns_timeout);
+    for record in records {
+        let subject = format!("{} {}", record.name, record.record_type);
+        match resolve(config.dns_resolver, record.clone(), timeout).await {
+            Ok((answers, _)) if answers.is_empty() => report.warning(subject, "no answers"),
+            Ok((answers, _)) if record.expected.is_empty() || answers_match(&record, &answers) => {
+                report.ok(subject, answers.join(", "))
+            }
+            Ok((answers, _)) => report.warning(
+                subject,
+                format!("unexpected answers {}", answers.join(", ")),
+            ),
+            Err(e) => report.failure(subject, &e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{answers_match, parse_records, query, Record};
+    use hickory_proto::op::{Message, MessageType};
+    use hickory_proto::rr::rdata::A;
+    use hickory_proto::rr::{Name, RData, RecordType};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, UdpSocket};
+    use std::time::Duration;
+
+    #[test]
+    fn parse_records_with_expected_values() {
+        let records =
+            parse_records("crates.io A=13.32.1.1|13.32.1.2, static.rust-lang.org cname=D1.cloudfront.net.,docs.rs AAAA")
+                .unwrap();
+        assert_eq!(
+            vec![
+                Record {
+                    name: "crates.io".into(),
+                    record_type: RecordType::A,
+                    expected: vec!["13.32.1.1".into(), "13.32.1.2".into()],
+                },
+                Record {
+                    name: "static.rust-lang.org".into(),
+                    record_type: RecordType::CNAME,
+                    expected: vec!["d1.cloudfront.net".into()],
+                },
+                Record {
+                    name: "docs.rs".into(),
+                    record_type: RecordType::AAAA,
+                    expected: vec![],
+                },
+            ],
+            records
+        );
+        assert!(parse_records("crates.io").is_err());
+
+        let answers = |answers: &[&str]| answers.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert!(answers_match(&records[0], &answers(&["13.32.1.2"])));
+        assert!(!answers_match(
+            &records[0],
+            &answers(&["13.32.1.2", "6.6.6.6"])
+        ));
+        assert!(!answers_match(&records[0], &answers(&[])));
+    }
+
+    // answers truncated over UDP and completely over TCP
+    fn answer(request: &Message, truncated: bool) -> Vec<u8> {
+        let mut response = Message::new();
+        response
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_truncated(truncated)
+            .add_query(request.queries()[0].clone());
+        if !truncated {
+            response.add_answer(hickory_proto::rr::Record::from_rdata(
+                Name::from_ascii("crates.io").unwrap(),
+                300,
+                RData::A(A([13, 32, 1, 1].into())),
+            ));
+        }
+        response.to_vec().unwrap()
+    }
+
+    #[test]
+    fn truncated_answers_are_retried_over_tcp() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let resolver = listener.local_addr().unwrap();
+        let socket = UdpSocket::bind(resolver).unwrap();
+        std::thread::spawn(move || {
+            let mut buffer = [0; 512];
+            let (len, peer) = socket.recv_from(&mut buffer).unwrap();
+            let request = Message::from_vec(&buffer[..len]).unwrap();
+            socket.send_to(&answer(&request, true), peer).unwrap();
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut buffer = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut buffer).unwrap();
+            let response = answer(&Message::from_vec(&buffer).unwrap(), false);
+            stream
+                .write_all(&(response.len() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&response).unwrap();
+        });
+
+        let record = &parse_records("crates.io A").unwrap()[0];
+        let (answers, _) = query(resolver, record, Duration::from_secs(5)).unwrap();
+        assert_eq!(vec!["13.32.1.1".to_string()], answers);
+    }
+}
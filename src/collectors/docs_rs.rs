@@ -0,0 +1,345 @@
+use super::{descs, unix_now, AlertDef, MetricDef, PanelDef, Refresh};
+use crate::check::Report;
+use crate::{Config, HttpClient};
+use anyhow::{Error, Result};
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use log::debug;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::IntGauge;
+use serde_json::Value;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+
+/// Queued crates, as `{"queue": [{"name", "version", "priority", "queued_at"}]}`
+const QUEUE_PATH: &str = "/api/v1/queue";
+/// Build status, as `{"last_build_finished_at": "..."}`
+const STATUS_PATH: &str = "/api/v1/status";
+
+const SCRAPE_SUCCESS: MetricDef = MetricDef::gauge(
+    "docsrs_scrape_success",
+    "Whether the last requests to the docs.rs queue and status endpoints succeeded",
+    &[],
+);
+const QUEUE_LENGTH: MetricDef = MetricDef::gauge(
+    "docsrs_queue_length",
+    "Number of crates waiting to be built",
+    &[],
+);
+const QUEUE_LENGTH_BY_PRIORITY: MetricDef = MetricDef::gauge(
+    "docsrs_queue_length_by_priority",
+    "Number of crates waiting to be built, by priority bucket (high, normal or low)",
+    &["priority"],
+);
+const OLDEST_QUEUED_AGE: MetricDef = MetricDef::gauge(
+    "docsrs_queue_oldest_age_seconds",
+    "Time the oldest crate of the queue has been waiting for, 0 if the queue is empty",
+    &[],
+);
+const LAST_BUILD_AGE: MetricDef = MetricDef::gauge(
+    "docsrs_last_build_age_seconds",
+    "Time elapsed since the last build finished",
+    &[],
+);
+
+pub(crate) const METRICS: &[MetricDef] = &[
+    SCRAPE_SUCCESS,
+    QUEUE_LENGTH,
+    QUEUE_LENGTH_BY_PRIORITY,
+    OLDEST_QUEUED_AGE,
+    LAST_BUILD_AGE,
+];
+
+pub(crate) const ALERTS: &[AlertDef] = &[
+    AlertDef {
+        name: "DocsRsQueueStuck",
+        expr: "{docsrs_queue_oldest_age_seconds} > 3600",
+        for_: "15m",
+        severity: "critical",
+        summary: "A crate has been waiting in the docs.rs queue for more than an hour",
+    },
+    AlertDef {
+        name: "DocsRsNoBuilds",
+        expr: "{docsrs_last_build_age_seconds} > 1800 and {docsrs_queue_length} > 0",
+        for_: "10m",
+        severity: "critical",
+        summary: "docs.rs didn't finish any build in 30 minutes while crates are queued",
+    },
+];
+
+pub(crate) const PANELS: &[PanelDef] = &[
+    PanelDef {
+        title: "docs.rs queue",
+        expr: "{docsrs_queue_length_by_priority}",
+        legend: "{{priority}}",
+        unit: "short",
+    },
+    PanelDef {
+        title: "docs.rs oldest queued crate",
+        expr: "{docsrs_queue_oldest_age_seconds}",
+        legend: "oldest",
+        unit: "s",
+    },
+];
+
+// docs.rs builds lower priorities first, 0 being the default
+const PRIORITY_BUCKETS: [&str; 3] = ["high", "normal", "low"];
+
+fn priority_bucket(priority: i32) -> usize {
+    match priority {
+        p if p < 0 => 0,
+        0 => 1,
+        _ => 2,
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Queue {
+    queue: Vec<QueuedCrate>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct QueuedCrate {
+    priority: i32,
+    queued_at: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Status {
+    last_build_finished_at: Option<DateTime<Utc>>,
+}
+
+// persisted state of the collector, see `Refresh::snapshot`
+#[derive(serde::Serialize, serde::Deserialize)]
+struct DocsRsSnapshot {
+    queue_by_priority: [i64; 3],
+    oldest_queued: i64,
+    last_build: i64,
+}
+
+#[derive(Clone)]
+pub struct DocsRs {
+    metrics: Arc<DocsRsMetrics>,
+    url: String,
+    descs: Vec<Desc>,
+    http: HttpClient,
+}
+
+struct DocsRsMetrics {
+    scrape_success: IntGauge,
+    queue_length: IntGauge,
+    // one per priority bucket, in order
+    queue_by_priority: Vec<IntGauge>,
+    oldest_queued_age: IntGauge,
+    last_build_age: IntGauge,
+    // unix times the ages are computed from on every scrape, 0 if unknown
+    oldest_queued: AtomicI64,
+    last_build: AtomicI64,
+}
+
+impl DocsRsMetrics {
+    fn update(&self, snapshot: &DocsRsSnapshot) {
+        self.queue_length
+            .set(snapshot.queue_by_priority.iter().sum());
+        for (gauge, length) in self
+            .queue_by_priority
+            .iter()
+            .zip(&snapshot.queue_by_priority)
+        {
+            gauge.set(*length);
+        }
+        self.oldest_queued
+            .store(snapshot.oldest_queued, Ordering::SeqCst);
+        self.last_build.store(snapshot.last_build, Ordering::SeqCst);
+    }
+}
+
+impl DocsRs {
+    pub fn new(config: &Config, http: HttpClient) -> Result<Self> {
+        let gauge = |def: MetricDef| IntGauge::with_opts(def.opts()).unwrap();
+        let metrics = DocsRsMetrics {
+            scrape_success: gauge(SCRAPE_SUCCESS),
+            queue_length: gauge(QUEUE_LENGTH),
+            queue_by_priority: PRIORITY_BUCKETS
+                .iter()
+                .map(|bucket| {
+                    IntGauge::with_opts(
+                        QUEUE_LENGTH_BY_PRIORITY
+                            .opts()
+                            .const_label("priority", *bucket),
+                    )
+                    .unwrap()
+                })
+                .collect(),
+            oldest_queued_age: gauge(OLDEST_QUEUED_AGE),
+            last_build_age: gauge(LAST_BUILD_AGE),
+            oldest_queued: AtomicI64::new(0),
+            last_build: AtomicI64::new(0),
+        };
+
+        Ok(Self {
+            metrics: Arc::new(metrics),
+            url: config.docsrs_url.clone(),
+            descs: descs(METRICS),
+            http,
+        })
+    }
+
+    async fn update_stats(&self) -> Result<(), Error> {
+        debug!("Updating docs.rs queue stats");
+
+        // the scheduler logs the error and backs off
+        let snapshot = fetch(&self.http, &self.url).await;
+        self.metrics
+            .scrape_success
+            .set(if snapshot.is_ok() { 1 } else { 0 });
+        self.metrics.update(&snapshot?);
+        Ok(())
+    }
+}
+
+async fn fetch(http: &HttpClient, url: &str) -> Result<DocsRsSnapshot> {
+    let queue = http
+        .send(http.get(&format!("{}{}", url, QUEUE_PATH)))
+        .await?
+        .error_for_status()?
+        .json::<Queue>()
+        .await?;
+    let status = http
+        .send(http.get(&format!("{}{}", url, STATUS_PATH)))
+        .await?
+        .error_for_status()?
+        .json::<Status>()
+        .await?;
+
+    Ok(summarize(&queue, &status))
+}
+
+fn summarize(queue: &Queue, status: &Status) -> DocsRsSnapshot {
+    let mut queue_by_priority = [0; 3];
+    for queued in queue.queue.iter() {
+        queue_by_priority[priority_bucket(queued.priority)] += 1;
+    }
+    DocsRsSnapshot {
+        queue_by_priority,
+        oldest_queued: queue
+            .queue
+            .iter()
+            .map(|queued| queued.queued_at.timestamp())
+            .min()
+            .unwrap_or(0),
+        last_build: status
+            .last_build_finished_at
+            .map_or(0, |finished| finished.timestamp()),
+    }
+}
+
+impl Refresh for DocsRs {
+    fn refresh(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(self.update_stats())
+    }
+
+    fn snapshot(&self) -> Result<Option<Value>, Error> {
+        let metrics = &self.metrics;
+        let mut queue_by_priority = [0; 3];
+        for (length, gauge) in queue_by_priority.iter_mut().zip(&metrics.queue_by_priority) {
+            *length = gauge.get();
+        }
+        Ok(Some(serde_json::to_value(DocsRsSnapshot {
+            queue_by_priority,
+            oldest_queued: metrics.oldest_queued.load(Ordering::SeqCst),
+            last_build: metrics.last_build.load(Ordering::SeqCst),
+        })?))
+    }
+
+    fn restore(&self, snapshot: Value) -> Result<(), Error> {
+        self.metrics.update(&serde_json::from_value(snapshot)?);
+        Ok(())
+    }
+}
+
+impl Collector for DocsRs {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let now = unix_now();
+        let age = |timestamp: i64| match timestamp {
+            0 => 0,
+            timestamp => (now - timestamp).max(0),
+        };
+
+        let metrics = &self.metrics;
+        metrics
+            .oldest_queued_age
+            .set(age(metrics.oldest_queued.load(Ordering::SeqCst)));
+        metrics
+            .last_build_age
+            .set(age(metrics.last_build.load(Ordering::SeqCst)));
+
+        let mut families = Vec::new();
+        families.extend(metrics.scrape_success.collect());
+        families.extend(metrics.queue_length.collect());
+        for gauge in metrics.queue_by_priority.iter() {
+            families.extend(gauge.collect());
+        }
+        families.extend(metrics.oldest_queued_age.collect());
+        // there's no last build to report until docs.rs finished one
+        if metrics.last_build.load(Ordering::SeqCst) > 0 {
+            families.extend(metrics.last_build_age.collect());
+        }
+        families
+    }
+}
+
+pub(crate) async fn check_config(config: &Config, http: &HttpClient, report: &mut Report) {
+    report.section("docs.rs (DOCSRS_URL)");
+
+    if config.docsrs_url.is_empty() {
+        report.ok("DOCSRS_URL", "no url, collector disabled");
+        return;
+    }
+
+    match fetch(http, &config.docsrs_url).await {
+        Ok(snapshot) => report.ok(
+            &config.docsrs_url,
+            format!(
+                "{} queued crates",
+                snapshot.queue_by_priority.iter().sum::<i64>()
+            ),
+        ),
+        Err(e) => report.failure(&config.docsrs_url, &e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{summarize, Queue, Status};
+    use serde_json::json;
+
+    #[test]
+    fn summarize_queue_by_priority() {
+        let queue: Queue = serde_json::from_value(json!({
+            "queue": [
+                { "name": "serde", "version": "1.0.203", "priority": -10, "queued_at": "2024-06-21T12:00:00Z" },
+                { "name": "tokio", "version": "1.38.0", "priority": 0, "queued_at": "2024-06-21T11:00:00Z" },
+                { "name": "syn", "version": "2.0.67", "priority": 0, "queued_at": "2024-06-21T12:30:00Z" },
+                { "name": "windows", "version": "0.57.0", "priority": 5, "queued_at": "2024-06-21T11:30:00Z" },
+            ]
+        }))
+        .unwrap();
+        let status: Status =
+            serde_json::from_value(json!({ "last_build_finished_at": null })).unwrap();
+
+        let snapshot = summarize(&queue, &status);
+        assert_eq!([1, 2, 1], snapshot.queue_by_priority);
+        assert_eq!(1_718_967_600, snapshot.oldest_queued);
+        assert_eq!(0, snapshot.last_build);
+
+        let empty = summarize(&Queue { queue: Vec::new() }, &status);
+        assert_eq!([0, 0, 0], empty.queue_by_priority);
+        assert_eq!(0, empty.oldest_queued);
+    }
+}
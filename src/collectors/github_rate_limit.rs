@@ -1,16 +1,105 @@
-use prometheus::{core::Collector, IntGauge, Opts};
+use prometheus::core::{Collector, Desc};
+use prometheus::{Gauge, IntCounter, IntGauge, Opts};
 
-use crate::Config;
+use super::series::SeriesMap;
+use super::{default_headers, descs, unix_now, AlertDef, MetricDef, PanelDef, Refresh};
+use crate::check::Report;
+use crate::{Config, HttpClient, Secret, SecretSource};
 use anyhow::{Context, Error, Result};
+use futures::future::{join_all, BoxFuture};
 use log::{debug, error};
-use reqwest::header::{ACCEPT, AUTHORIZATION, USER_AGENT};
-use reqwest::{Client, Method, Request};
+use reqwest::Request;
+use serde_json::Value;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use tokio::time::Duration;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-const GH_API_USER_ENDPOINT: &str = "https://api.github.com/user";
-const GH_API_RATE_LIMIT_ENDPOINT: &str = "https://api.github.com/rate_limit";
+const SCRAPE_SUCCESS: MetricDef = MetricDef::gauge(
+    "github_rate_limit_scrape_success",
+    "Whether the last GitHub rate limit request for this token succeeded",
+    &["username"],
+);
+const LIMIT: MetricDef = MetricDef::gauge(
+    "github_rate_limit_limit",
+    "GitHub API total rate limit",
+    &["username", "product"],
+);
+const REMAINING: MetricDef = MetricDef::gauge(
+    "github_rate_limit_remaining",
+    "GitHub API remaining rate limit",
+    &["username", "product"],
+);
+const RESET: MetricDef = MetricDef::gauge(
+    "github_rate_limit_reset",
+    "GitHub API rate limit reset time",
+    &["username", "product"],
+);
+const USED: MetricDef = MetricDef::gauge(
+    "github_rate_limit_used",
+    "GitHub API rate limit used in the current window",
+    &["username", "product"],
+);
+const UTILIZATION: MetricDef = MetricDef::gauge(
+    "github_rate_limit_utilization_ratio",
+    "Ratio of the GitHub API rate limit used in the current window",
+    &["username", "product"],
+);
+const SECONDS_UNTIL_RESET: MetricDef = MetricDef::gauge(
+    "github_rate_limit_seconds_until_reset",
+    "Seconds until the GitHub API rate limit window resets",
+    &["username", "product"],
+);
+const EXHAUSTED: MetricDef = MetricDef::counter(
+    "github_rate_limit_exhausted_total",
+    "Number of rate limit windows in which the GitHub API rate limit ran out",
+    &["username", "product"],
+);
+
+pub(crate) const METRICS: &[MetricDef] = &[
+    SCRAPE_SUCCESS,
+    LIMIT,
+    REMAINING,
+    RESET,
+    USED,
+    UTILIZATION,
+    SECONDS_UNTIL_RESET,
+    EXHAUSTED,
+];
+
+pub(crate) const ALERTS: &[AlertDef] = &[
+    AlertDef {
+        name: "GitHubRateLimitLow",
+        expr: "{github_rate_limit_remaining} / {github_rate_limit_limit} < 0.1",
+        for_: "5m",
+        severity: "warning",
+        summary: "Less than 10% of the {{ $labels.product }} GitHub rate limit of {{ $labels.username }} remains",
+    },
+    AlertDef {
+        name: "GitHubRateLimitScrapeFailing",
+        expr: "{github_rate_limit_scrape_success} == 0",
+        for_: "15m",
+        severity: "warning",
+        summary: "Unable to fetch the GitHub rate limit of {{ $labels.username }}",
+    },
+];
+
+pub(crate) const PANELS: &[PanelDef] = &[
+    PanelDef {
+        title: "GitHub rate limit remaining",
+        expr: "{github_rate_limit_remaining} / {github_rate_limit_limit}",
+        legend: "{{username}} ({{product}})",
+        unit: "percentunit",
+    },
+    PanelDef {
+        title: "GitHub rate limit exhaustions",
+        expr: "increase({github_rate_limit_exhausted_total}[1h])",
+        legend: "{{username}} ({{product}})",
+        unit: "short",
+    },
+];
+
+// time after which looking up the username of a token is given up
+const USER_TIMEOUT: Duration = Duration::from_secs(30);
 
 enum GithubReqBuilder {
     User,
@@ -18,182 +107,598 @@ enum GithubReqBuilder {
 }
 
 impl GithubReqBuilder {
-    fn build_request(&self, client: &Client, token: &str) -> Result<Request, Error> {
-        let rb = match self {
-            Self::User => client.request(Method::GET, GH_API_USER_ENDPOINT),
-            Self::RateLimit => client.request(Method::GET, GH_API_RATE_LIMIT_ENDPOINT),
+    fn build_request(
+        &self,
+        client: &HttpClient,
+        api_url: &str,
+        token: &Secret,
+    ) -> Result<Request, Error> {
+        let path = match self {
+            Self::User => "/user",
+            Self::RateLimit => "/rate_limit",
         };
 
-        rb.header(
-            USER_AGENT,
-            "https://github.com/rust-lang/monitorbot (infra@rust-lang.org)",
-        )
-        .header(AUTHORIZATION, format!("{} {}", "token", token))
-        .header(ACCEPT, "application/vnd.github.v3+json")
-        .build()
-        .map_err(Error::from)
+        client
+            .get(&format!("{}{}", api_url, path))
+            .headers(default_headers(token))
+            .build()
+            .map_err(Error::from)
     }
 }
 
 #[derive(Clone)]
 pub struct GitHubRateLimit {
-    users: Vec<User>,
+    tokens: SecretSource,
+    ttl: Option<Duration>,
+    users: Arc<RwLock<Vec<User>>>,
+    descs: Vec<Desc>,
+    api_url: String,
+    http: HttpClient,
 }
 
 impl GitHubRateLimit {
-    pub async fn new(config: &Config) -> Result<Self, Error> {
-        let tokens: Vec<String> = config
-            .gh_rate_limit_tokens
-            .split(',')
-            .map(|v| v.trim().to_string())
-            .collect();
-
-        let users = Self::get_users_for_tokens(tokens)
+    pub async fn new(config: &Config, http: HttpClient) -> Result<Self, Error> {
+        let rv = Self {
+            tokens: config.gh_rate_limit_tokens.clone(),
+            ttl: config.series_ttl(config.gh_rate_limit_stats_cache_refresh),
+            users: Arc::new(RwLock::new(Vec::new())),
+            descs: descs(METRICS),
+            api_url: config.github_api_url.clone(),
+            http,
+        };
+
+        // a revoked token is skipped rather than preventing monitorbot from
+        // starting, it's looked up again on every refresh
+        rv.reload_users()
             .await
-            .context("Unable to get usernames for rate limit stats")?;
+            .context("Unable to load rate limit tokens")?;
+
+        Ok(rv)
+    }
 
-        let rv = Self { users };
+    async fn get_user_for_token(&self, entry: TokenEntry) -> Result<User, Error> {
+        let TokenEntry { alias, token } = entry;
+        let name = match &alias {
+            // the alias is used as label so the username isn't needed, aliased
+            // tokens are verified by `monitorbot check-config` instead
+            Some(alias) => alias.clone(),
+            None => self.get_github_api_username(&token).await?,
+        };
+        Ok(User {
+            token,
+            aliased: alias.is_some(),
+            scrape_success: scrape_success_gauge(&name),
+            name,
+            products: Arc::new(Mutex::new(SeriesMap::new(self.ttl))),
+        })
+    }
 
-        let refresh_rate = config.gh_rate_limit_stats_cache_refresh;
-        let mut rv2 = rv.clone();
-        tokio::spawn(async move {
-            loop {
-                if let Err(e) = rv2.update_stats().await {
-                    error!("{:#?}", e);
-                }
+    // pick up tokens that were added, removed or rotated since the last refresh
+    async fn reload_users(&self) -> Result<(), Error> {
+        let tokens = parse_tokens(&self.tokens.get()?)?;
+        let current = self.users.read().unwrap().clone();
 
-                tokio::time::delay_for(Duration::from_secs(refresh_rate)).await;
+        let mut users = Vec::with_capacity(tokens.len());
+        for entry in tokens {
+            if let Some(user) = current.iter().find(|user| user.matches(&entry)) {
+                users.push(User {
+                    token: entry.token,
+                    ..user.clone()
+                });
+                continue;
             }
-        });
-
-        Ok(rv)
-    }
 
-    async fn get_users_for_tokens(tokens: Vec<String>) -> Result<Vec<User>, Error> {
-        let mut result = Vec::new();
-        for token in &tokens {
-            result.push(User {
-                token: token.to_owned(),
-                name: GitHubRateLimit::get_github_api_username(token).await?,
-                products: Arc::new(Mutex::new(HashMap::new())),
-            });
+            match self.get_user_for_token(entry).await {
+                Ok(user) => {
+                    debug!("Added rate limit token for {}", user.name);
+                    users.push(user);
+                }
+                Err(e) => error!("Unable to get username for rate limit token: {:?}", e),
+            }
         }
-        Ok(result)
+
+        *self.users.write().unwrap() = users;
+        Ok(())
     }
 
-    async fn get_github_api_username(token: &str) -> Result<String, Error> {
+    async fn get_github_api_username(&self, token: &Secret) -> Result<String, Error> {
         #[derive(serde::Deserialize)]
         struct GithubUser {
             pub login: String,
         }
 
-        let client = reqwest::Client::new();
-        let req = GithubReqBuilder::User.build_request(&client, token)?;
+        let req = GithubReqBuilder::User.build_request(&self.http, &self.api_url, token)?;
 
-        let u = client
-            .execute(req)
-            .await?
-            .error_for_status()?
-            .json::<GithubUser>()
-            .await?;
+        // a hanging request must not block the registration of the collectors
+        let u = tokio::time::timeout(USER_TIMEOUT, async {
+            self.http
+                .execute(req)
+                .await?
+                .error_for_status()?
+                .json::<GithubUser>()
+                .await
+                .map_err(Error::from)
+        })
+        .await
+        .context("timed out getting the username of a token")??;
 
         Ok(u.login)
     }
 
-    async fn update_stats(&mut self) -> Result<(), Error> {
+    async fn update_stats(&self) -> Result<(), Error> {
+        debug!("Updating rate limit stats");
+
+        self.reload_users()
+            .await
+            .context("Unable to reload rate limit tokens")?;
+        let users = self.users.read().unwrap().clone();
+
+        let results = join_all(users.iter().map(|user| self.update_user_stats(user))).await;
+
+        let mut failed = 0;
+        for (user, result) in users.iter().zip(results) {
+            match result {
+                Ok(()) => user.scrape_success.set(1),
+                Err(e) => {
+                    error!(
+                        "Unable to update rate limit stats for {}: {:?}",
+                        user.name, e
+                    );
+                    user.scrape_success.set(0);
+                    failed += 1;
+                }
+            }
+        }
+
+        if failed > 0 {
+            anyhow::bail!(
+                "failed to update rate limit stats for {} of {} tokens",
+                failed,
+                users.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn update_user_stats(&self, user: &User) -> Result<(), Error> {
         #[derive(Debug, serde::Deserialize)]
         struct ResponseBody {
             resources: HashMap<String, ResponseResource>,
         }
 
-        #[derive(Debug, serde::Deserialize)]
-        struct ResponseResource {
-            limit: i64,
-            remaining: i64,
-            reset: i64,
-        }
+        let req = GithubReqBuilder::RateLimit
+            .build_request(&self.http, &self.api_url, &user.token)
+            .context("Unable to build request to update stats")?;
 
-        debug!("Updating rate limit stats");
+        let response = self
+            .http
+            .execute(req)
+            .await
+            .context("Unable to execute request to update stats")?
+            .error_for_status()
+            .context("GitHub returned an error status for rate limit stats")?;
 
-        let client = reqwest::Client::new();
-        for user in self.users.iter_mut() {
-            let req = GithubReqBuilder::RateLimit
-                .build_request(&client, &user.token)
-                .context("Unable to build request to update stats")?;
+        let data: ResponseBody = response
+            .json()
+            .await
+            .context("Unable to deserialize rate limit stats")?;
 
-            let response = client
-                .execute(req)
-                .await
-                .context("Unable to execute request to update stats")?;
+        let mut user_products = user.products.lock().unwrap();
+        for (product_name, resource) in data.resources.iter() {
+            user_products
+                .refresh_with(product_name.to_string(), || {
+                    ProductMetrics::new(&user.name, product_name)
+                })
+                .update(resource);
+        }
+        // products GitHub doesn't report anymore
+        user_products.retain(|product_name, _| data.resources.contains_key(product_name));
 
-            let data: ResponseBody = response
-                .json()
-                .await
-                .context("Unable to deserialize rate limit stats")?;
+        Ok(())
+    }
+}
 
-            let mut user_products = user.products.lock().unwrap();
-            for (product_name, resource) in data.resources.iter() {
-                let product = user_products
-                    .entry(product_name.to_string())
-                    .or_insert_with(|| ProductMetrics::new(&user.name, product_name));
+impl Refresh for GitHubRateLimit {
+    fn refresh(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(self.update_stats())
+    }
 
-                product.limit.set(resource.limit);
-                product.remaining.set(resource.remaining);
-                product.reset.set(resource.reset);
-            }
+    fn snapshot(&self) -> Result<Option<Value>, Error> {
+        let mut users: HashMap<String, HashMap<String, ProductSnapshot>> = HashMap::new();
+        for user in self.users.read().unwrap().iter() {
+            let products = user.products.lock().unwrap();
+            users.insert(
+                user.name.clone(),
+                products
+                    .iter()
+                    .map(|(name, product)| (name.clone(), product.snapshot()))
+                    .collect(),
+            );
         }
+        Ok(Some(serde_json::to_value(users)?))
+    }
 
+    fn restore(&self, snapshot: Value) -> Result<(), Error> {
+        let mut users: HashMap<String, HashMap<String, ProductSnapshot>> =
+            serde_json::from_value(snapshot)?;
+        // users whose token was removed since the snapshot are skipped
+        for user in self.users.read().unwrap().iter() {
+            let mut products = user.products.lock().unwrap();
+            for (name, snapshot) in users.remove(&user.name).unwrap_or_default() {
+                products
+                    .refresh_with(name.clone(), || ProductMetrics::new(&user.name, &name))
+                    .restore(&snapshot);
+            }
+        }
         Ok(())
     }
 }
 
 impl Collector for GitHubRateLimit {
     fn desc(&self) -> std::vec::Vec<&prometheus::core::Desc> {
-        // descriptions are being defined in the initialization of the metrics options
-        Vec::default()
+        self.descs.iter().collect()
     }
 
     fn collect(&self) -> std::vec::Vec<prometheus::proto::MetricFamily> {
+        let now = unix_now();
+
         let mut metrics = Vec::new();
-        for user in self.users.iter() {
-            for product in user.products.lock().unwrap().values() {
+        for user in self.users.read().unwrap().iter() {
+            metrics.extend(user.scrape_success.collect());
+            let mut products = user.products.lock().unwrap();
+            products.expire();
+            for product in products.values() {
+                product.update_seconds_until_reset(now);
                 metrics.extend(product.limit.collect());
                 metrics.extend(product.remaining.collect());
                 metrics.extend(product.reset.collect());
+                metrics.extend(product.used.collect());
+                metrics.extend(product.utilization.collect());
+                metrics.extend(product.seconds_until_reset.collect());
+                metrics.extend(product.exhausted.collect());
             }
         }
         metrics
     }
 }
 
+/// An entry of the rate limit tokens list, either `token` or `alias=token`.
+#[derive(Debug, PartialEq)]
+struct TokenEntry {
+    // label to use instead of the GitHub username of the token
+    alias: Option<String>,
+    token: Secret,
+}
+
+fn parse_tokens(tokens: &Secret) -> Result<Vec<TokenEntry>, Error> {
+    tokens
+        .expose_secret()
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .enumerate()
+        .map(|(i, entry)| match entry.split_once('=') {
+            Some((alias, token)) => {
+                let (alias, token) = (alias.trim(), token.trim());
+                // the entry itself isn't part of the error, it contains the token
+                anyhow::ensure!(
+                    !alias.is_empty(),
+                    "rate limit token #{} has an empty alias",
+                    i + 1
+                );
+                anyhow::ensure!(!token.is_empty(), "rate limit token {} is empty", alias);
+                Ok(TokenEntry {
+                    alias: Some(alias.to_string()),
+                    token: Secret::new(token.to_string()),
+                })
+            }
+            None => Ok(TokenEntry {
+                alias: None,
+                token: Secret::new(entry.to_string()),
+            }),
+        })
+        .collect()
+}
+
 #[derive(Clone)]
 struct User {
-    token: String,
+    token: Secret,
+    // whether `name` is an alias instead of the token's github username
+    aliased: bool,
     name: String,
-    products: Arc<Mutex<HashMap<String, ProductMetrics>>>,
+    // whether the last rate limit request for this token succeeded
+    scrape_success: IntGauge,
+    products: Arc<Mutex<SeriesMap<String, ProductMetrics>>>,
+}
+
+impl User {
+    fn matches(&self, entry: &TokenEntry) -> bool {
+        match &entry.alias {
+            // aliased tokens keep their metrics when the token is rotated
+            Some(alias) => self.aliased && self.name == *alias,
+            None => !self.aliased && self.token == entry.token,
+        }
+    }
+}
+
+fn scrape_success_gauge(user: &str) -> IntGauge {
+    IntGauge::with_opts(SCRAPE_SUCCESS.opts().const_label("username", user)).unwrap()
+}
+
+/// What GitHub tells about a token, used by `monitorbot check-config`.
+pub(crate) struct TokenInfo {
+    pub(crate) login: String,
+    // `None` for tokens without OAuth scopes, e.g. fine-grained tokens
+    pub(crate) scopes: Option<Vec<String>>,
+    pub(crate) core: ResponseResource,
+}
+
+impl TokenInfo {
+    pub(crate) fn report(&self, report: &mut Report, subject: &str) {
+        let scopes = match &self.scopes {
+            Some(scopes) if scopes.is_empty() => "no scopes".to_string(),
+            Some(scopes) => format!("scopes {}", scopes.join(", ")),
+            None => "fine-grained token".to_string(),
+        };
+        let message = format!(
+            "authenticated as {}, {}, core rate limit {}/{} remaining",
+            self.login, scopes, self.core.remaining, self.core.limit
+        );
+
+        if self.core.remaining * 10 < self.core.limit {
+            report.warning(subject, message);
+        } else {
+            report.ok(subject, message);
+        }
+    }
+}
+
+/// Looks up the owner, scopes and core rate limit of a token.
+pub(crate) async fn verify_token(
+    http: &HttpClient,
+    api_url: &str,
+    token: &Secret,
+) -> Result<TokenInfo, Error> {
+    #[derive(serde::Deserialize)]
+    struct GithubUser {
+        login: String,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct ResponseBody {
+        resources: HashMap<String, ResponseResource>,
+    }
+
+    let response = http
+        .execute(GithubReqBuilder::User.build_request(http, api_url, token)?)
+        .await?
+        .error_for_status()?;
+    let scopes = response
+        .headers()
+        .get("x-oauth-scopes")
+        .and_then(|scopes| scopes.to_str().ok())
+        .map(|scopes| {
+            scopes
+                .split(',')
+                .map(str::trim)
+                .filter(|scope| !scope.is_empty())
+                .map(String::from)
+                .collect()
+        });
+    let user: GithubUser = response.json().await?;
+
+    let mut rate_limit: ResponseBody = http
+        .execute(GithubReqBuilder::RateLimit.build_request(http, api_url, token)?)
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(TokenInfo {
+        login: user.login,
+        scopes,
+        core: rate_limit
+            .resources
+            .remove("core")
+            .context("GitHub didn't report the core rate limit")?,
+    })
+}
+
+pub(crate) async fn check_config(config: &Config, http: &HttpClient, report: &mut Report) {
+    report.section("Rate limit tokens (RATE_LIMIT_TOKENS)");
+
+    let tokens = match config.gh_rate_limit_tokens.get() {
+        Ok(tokens) => match parse_tokens(&tokens) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                report.failure("RATE_LIMIT_TOKENS", &e);
+                return;
+            }
+        },
+        Err(e) => {
+            report.failure("RATE_LIMIT_TOKENS", &e);
+            return;
+        }
+    };
+    if tokens.is_empty() {
+        report.error("RATE_LIMIT_TOKENS", "no tokens configured");
+    }
+
+    for (i, entry) in tokens.iter().enumerate() {
+        let subject = match &entry.alias {
+            Some(alias) => alias.clone(),
+            None => format!("token #{}", i + 1),
+        };
+        match verify_token(http, &config.github_api_url, &entry.token).await {
+            Ok(info) => info.report(report, &subject),
+            Err(e) => report.failure(subject, &e),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct ResponseResource {
+    limit: i64,
+    remaining: i64,
+    reset: i64,
+    // not reported by older GitHub Enterprise versions
+    used: Option<i64>,
+}
+
+// persisted state of a product, see `Refresh::snapshot`
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ProductSnapshot {
+    #[serde(flatten)]
+    resource: ResponseResource,
+    exhausted: i64,
+    last_exhausted_window: Option<i64>,
 }
 
 struct ProductMetrics {
     limit: IntGauge,
     remaining: IntGauge,
     reset: IntGauge,
+    used: IntGauge,
+    utilization: Gauge,
+    seconds_until_reset: IntGauge,
+    exhausted: IntCounter,
+    // reset time of the last window in which we saw the rate limit run out,
+    // so that every window is only counted once
+    last_exhausted_window: Option<i64>,
 }
 
 impl ProductMetrics {
     fn new(user: &str, product: &str) -> Self {
-        let gauge = |name, help| -> IntGauge {
-            IntGauge::with_opts(
-                Opts::new(name, help)
-                    .namespace("github_rate_limit")
-                    .const_label("username", user)
-                    .const_label("product", product),
-            )
-            .unwrap()
+        let opts = |def: MetricDef| -> Opts {
+            def.opts()
+                .const_label("username", user)
+                .const_label("product", product)
         };
+        let gauge = |def| -> IntGauge { IntGauge::with_opts(opts(def)).unwrap() };
         Self {
-            limit: gauge("limit", "GitHub API total rate limit"),
-            remaining: gauge("remaining", "GitHub API remaining rate limit"),
-            reset: gauge("reset", "GitHub API rate limit reset time"),
+            limit: gauge(LIMIT),
+            remaining: gauge(REMAINING),
+            reset: gauge(RESET),
+            used: gauge(USED),
+            utilization: Gauge::with_opts(opts(UTILIZATION)).unwrap(),
+            seconds_until_reset: gauge(SECONDS_UNTIL_RESET),
+            exhausted: IntCounter::with_opts(opts(EXHAUSTED)).unwrap(),
+            last_exhausted_window: None,
+        }
+    }
+
+    fn update(&mut self, resource: &ResponseResource) {
+        let used = resource.used.unwrap_or(resource.limit - resource.remaining);
+
+        self.limit.set(resource.limit);
+        self.remaining.set(resource.remaining);
+        self.reset.set(resource.reset);
+        self.used.set(used);
+        self.utilization.set(if resource.limit > 0 {
+            used as f64 / resource.limit as f64
+        } else {
+            0.0
+        });
+
+        if resource.remaining == 0 && self.last_exhausted_window != Some(resource.reset) {
+            self.exhausted.inc();
+            self.last_exhausted_window = Some(resource.reset);
         }
     }
+
+    fn snapshot(&self) -> ProductSnapshot {
+        ProductSnapshot {
+            resource: ResponseResource {
+                limit: self.limit.get(),
+                remaining: self.remaining.get(),
+                reset: self.reset.get(),
+                used: Some(self.used.get()),
+            },
+            exhausted: self.exhausted.get(),
+            last_exhausted_window: self.last_exhausted_window,
+        }
+    }
+
+    fn restore(&mut self, snapshot: &ProductSnapshot) {
+        // the window of the snapshot was already counted before the restart
+        self.last_exhausted_window = snapshot.last_exhausted_window;
+        self.update(&snapshot.resource);
+        self.exhausted.inc_by(snapshot.exhausted);
+    }
+
+    fn update_seconds_until_reset(&self, now: i64) {
+        self.seconds_until_reset
+            .set((self.reset.get() - now).max(0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_tokens, ProductMetrics, ResponseResource, TokenEntry};
+    use crate::Secret;
+
+    #[test]
+    fn parse_tokens_with_aliases() {
+        let tokens = parse_tokens(&Secret::new(
+            "ghp_first, triagebot-prod=ghp_second,".to_string(),
+        ))
+        .unwrap();
+        assert_eq!(
+            vec![
+                TokenEntry {
+                    alias: None,
+                    token: Secret::new("ghp_first".to_string()),
+                },
+                TokenEntry {
+                    alias: Some("triagebot-prod".to_string()),
+                    token: Secret::new("ghp_second".to_string()),
+                },
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn parse_tokens_rejects_empty_aliases() {
+        let err = parse_tokens(&Secret::new("ghp_first, =ghp_second".to_string())).unwrap_err();
+        assert_eq!("rate limit token #2 has an empty alias", err.to_string());
+        assert!(parse_tokens(&Secret::new("triagebot-prod=".to_string())).is_err());
+    }
+
+    fn resource(remaining: i64, reset: i64) -> ResponseResource {
+        ResponseResource {
+            limit: 5000,
+            remaining,
+            reset,
+            used: None,
+        }
+    }
+
+    #[test]
+    fn derived_metrics() {
+        let mut product = ProductMetrics::new("user", "core");
+        product.update(&resource(1250, 1_000));
+        product.update_seconds_until_reset(400);
+
+        assert_eq!(3750, product.used.get());
+        assert!((product.utilization.get() - 0.75).abs() < f64::EPSILON);
+        assert_eq!(600, product.seconds_until_reset.get());
+
+        product.update_seconds_until_reset(1_200);
+        assert_eq!(0, product.seconds_until_reset.get());
+    }
+
+    #[test]
+    fn exhaustion_counted_once_per_window() {
+        let mut product = ProductMetrics::new("user", "core");
+        product.update(&resource(10, 1_000));
+        product.update(&resource(0, 1_000));
+        product.update(&resource(0, 1_000));
+        assert_eq!(1, product.exhausted.get());
+
+        product.update(&resource(5000, 2_000));
+        product.update(&resource(0, 2_000));
+        assert_eq!(2, product.exhausted.get());
+    }
 }
@@ -1,23 +1,84 @@
-use super::default_headers;
-use crate::Config;
-use anyhow::{Context, Result};
+use super::github_rate_limit::verify_token;
+use super::series::SeriesMap;
+use super::{default_headers, descs, unix_now, AlertDef, MetricDef, PanelDef, Refresh};
+use crate::check::Report;
+use crate::{Config, HttpClient, SecretSource};
+use anyhow::{Context, Error, Result};
+use futures::future::BoxFuture;
 use log::{debug, error};
-use prometheus::core::AtomicI64;
-use prometheus::core::{Desc, GenericGauge};
+use prometheus::core::Desc;
 use prometheus::proto::MetricFamily;
-use prometheus::{core::Collector, IntGauge, Opts};
+use prometheus::{core::Collector, IntCounter, IntGauge};
 use reqwest::header::{HeaderValue, LINK};
-use reqwest::{Client, Response};
-use std::collections::HashMap;
+use reqwest::Response;
+use serde_json::Value;
+use std::collections::HashSet;
 use std::sync::{Arc, RwLock};
-use tokio::time::Duration;
 
-const GH_RUNNERS_ENDPOINT: &str =
-    "https://api.github.com/repos/{owner_repo}/actions/runners?per_page=100";
+const GH_RUNNERS_ENDPOINT: &str = "{api_url}/repos/{owner_repo}/actions/runners?per_page=100";
+
+const ONLINE: MetricDef =
+    MetricDef::gauge("gha_runner_online", "runner is online", &["repo", "runner"]);
+const BUSY: MetricDef = MetricDef::gauge("gha_runner_busy", "runner is busy", &["repo", "runner"]);
+const OFFLINE_DURATION: MetricDef = MetricDef::gauge(
+    "gha_runner_offline_seconds",
+    "Time the runner has been offline for since monitorbot first saw it offline, 0 if online",
+    &["repo", "runner"],
+);
+const TRANSITIONS: MetricDef = MetricDef::counter(
+    "gha_runner_state_transitions_total",
+    "Number of times the runner went online or offline",
+    &["repo", "runner"],
+);
+const DEREGISTERED: MetricDef = MetricDef::gauge(
+    "gha_runner_deregistered",
+    "Whether the runner is no longer registered, reported until the retention window elapses",
+    &["repo", "runner"],
+);
+
+pub(crate) const METRICS: &[MetricDef] =
+    &[ONLINE, BUSY, OFFLINE_DURATION, TRANSITIONS, DEREGISTERED];
+
+pub(crate) const ALERTS: &[AlertDef] = &[
+    AlertDef {
+        name: "GitHubRunnerOffline",
+        expr: "{gha_runner_online} == 0",
+        for_: "10m",
+        severity: "warning",
+        summary: "Runner {{ $labels.runner }} of {{ $labels.repo }} is offline",
+    },
+    AlertDef {
+        name: "GitHubRunnerFlapping",
+        expr: "increase({gha_runner_state_transitions_total}[1h]) >= 4",
+        for_: "0m",
+        severity: "warning",
+        summary: "Runner {{ $labels.runner }} of {{ $labels.repo }} keeps going online and offline",
+    },
+];
+
+pub(crate) const PANELS: &[PanelDef] = &[
+    PanelDef {
+        title: "Online runners",
+        expr: "sum by (repo) ({gha_runner_online})",
+        legend: "{{repo}}",
+        unit: "short",
+    },
+    PanelDef {
+        title: "Busy runners",
+        expr: "sum by (repo) ({gha_runner_busy})",
+        legend: "{{repo}}",
+        unit: "short",
+    },
+    PanelDef {
+        title: "Offline runners",
+        expr: "{gha_runner_offline_seconds} > 0",
+        legend: "{{repo}} {{runner}}",
+        unit: "s",
+    },
+];
 
 #[derive(Debug, serde::Deserialize)]
 struct ApiResponse {
-    #[expect(dead_code)]
     total_count: usize,
     runners: Vec<Runner>,
 }
@@ -36,69 +97,56 @@ struct Runner {
 #[derive(Clone)]
 pub struct GithubRunners {
     //api token to use
-    token: String,
+    token: SecretSource,
     // repos to track gha runners
     repos: Vec<String>,
-    // actual metrics
-    metrics: Arc<RwLock<Vec<IntGauge>>>,
-    // default metric description
-    desc: Desc,
-    http: Client,
+    // actual metrics, keyed by repo and runner name
+    metrics: Arc<RwLock<SeriesMap<(String, String), RunnerMetrics>>>,
+    // time deregistered runners are still reported for (in seconds)
+    deregistered_retention: i64,
+    // descriptions of the exported metrics
+    descs: Vec<Desc>,
+    api_url: String,
+    http: HttpClient,
 }
 
 impl GithubRunners {
-    pub async fn new(config: &Config, http: Client) -> Result<Self> {
-        let token = config.github_token.to_string();
+    pub async fn new(config: &Config, http: HttpClient) -> Result<Self> {
+        let token = config.github_token.clone();
         let repos: Vec<String> = config
             .gha_runners_repos
             .split(',')
             .map(|v| v.trim().to_string())
             .collect();
 
-        let rv = Self {
+        Ok(Self {
             token,
             repos,
+            api_url: config.github_api_url.clone(),
             http,
-            metrics: Arc::new(RwLock::new(Vec::new())),
-            desc: Desc::new(
-                String::from("gha_runner"),
-                String::from("GHA runner's status"),
-                Vec::new(),
-                HashMap::new(),
-            )
-            .unwrap(),
-        };
-
-        let refresh_rate = config.gha_runners_cache_refresh;
-        let mut rv2 = rv.clone();
-        tokio::spawn(async move {
-            loop {
-                if let Err(e) = rv2.update_stats().await {
-                    error!("{:#?}", e);
-                }
-
-                tokio::time::delay_for(Duration::from_secs(refresh_rate)).await;
-            }
-        });
-
-        Ok(rv)
+            metrics: Arc::new(RwLock::new(SeriesMap::new(
+                config.series_ttl(config.gha_runners_cache_refresh),
+            ))),
+            deregistered_retention: config.gha_runners_deregistered_retention as i64,
+            descs: descs(METRICS),
+        })
     }
 
-    async fn update_stats(&mut self) -> Result<()> {
-        let mut gauges = Vec::with_capacity(self.repos.len() * 2);
+    async fn update_stats(&self) -> Result<()> {
+        let token = self.token.get()?;
         for repo in self.repos.iter() {
             let mut url: Option<String> = String::from(GH_RUNNERS_ENDPOINT)
+                .replace("{api_url}", &self.api_url)
                 .replace("{owner_repo}", repo)
                 .into();
 
             debug!("Updating runner's stats");
 
+            let mut runners = Vec::new();
             while let Some(endpoint) = url.take() {
                 let response = self
                     .http
-                    .get(&endpoint)
-                    .headers(default_headers(&self.token))
-                    .send()
+                    .send(self.http.get(&endpoint).headers(default_headers(&token)))
                     .await?;
 
                 url = guard_rate_limited(&response)?
@@ -106,53 +154,207 @@ impl GithubRunners {
                     .map(|res| next_uri(res.headers().get(LINK)))?;
 
                 let resp = response.json::<ApiResponse>().await?;
+                runners.extend(resp.runners);
+            }
 
-                for runner in resp.runners.iter() {
-                    let online = metric_factory(
-                        "online",
-                        "runner is online",
-                        &self.desc.fq_name,
-                        repo,
-                        &runner.name,
-                    );
-                    online.set(if runner.status == "online" { 1 } else { 0 });
-                    gauges.push(online);
-
-                    let busy = metric_factory(
-                        "busy",
-                        "runner is busy",
-                        &self.desc.fq_name,
-                        repo,
-                        &runner.name,
-                    );
-                    busy.set(if runner.busy { 1 } else { 0 });
-                    gauges.push(busy);
-                }
+            // lock and update the state of this repo's runners
+            let now = unix_now();
+            let mut guard = self.metrics.write().unwrap();
+            for runner in runners.iter() {
+                let metrics = guard.refresh_with((repo.clone(), runner.name.clone()), || {
+                    RunnerMetrics::new(repo, &runner.name)
+                });
+                metrics.update(runner.status == "online", runner.busy, now);
             }
+
+            // runners missing from the response were deregistered, they're
+            // kept around until the retention window elapses
+            let seen: HashSet<&str> = runners.iter().map(|r| r.name.as_str()).collect();
+            let missing: Vec<String> = guard
+                .iter()
+                .filter(|((r, name), _)| r == repo && !seen.contains(name.as_str()))
+                .map(|((_, name), _)| name.clone())
+                .collect();
+            for name in missing {
+                guard
+                    .refresh_with((repo.clone(), name.clone()), || {
+                        RunnerMetrics::new(repo, &name)
+                    })
+                    .deregister(now);
+            }
+            guard.retain(|(r, _), metrics| {
+                r != repo
+                    || metrics.deregistered_at == 0
+                    || now - metrics.deregistered_at < self.deregistered_retention
+            });
         }
 
-        // lock and replace old data
-        let mut guard = self.metrics.write().unwrap();
-        *guard = gauges;
+        Ok(())
+    }
+}
+
+// persisted state of a runner, see `Refresh::snapshot`
+#[derive(serde::Serialize, serde::Deserialize)]
+struct RunnerSnapshot {
+    repo: String,
+    runner: String,
+    online: i64,
+    busy: i64,
+    // the fields below were added later, older snapshots don't have them
+    #[serde(default)]
+    offline_since: i64,
+    #[serde(default)]
+    transitions: i64,
+    #[serde(default)]
+    deregistered_at: i64,
+}
+
+struct RunnerMetrics {
+    online: IntGauge,
+    busy: IntGauge,
+    offline_duration: IntGauge,
+    transitions: IntCounter,
+    deregistered: IntGauge,
+    // whether the runner was online at the last refresh, `None` until it's
+    // seen so that its first state doesn't count as a transition
+    last_online: Option<bool>,
+    // unix time the runner was first seen offline, 0 while it's online
+    offline_since: i64,
+    // unix time the runner was first missing from the api, 0 while it's registered
+    deregistered_at: i64,
+}
+
+impl RunnerMetrics {
+    fn new(repo: &str, runner: &str) -> Self {
+        Self {
+            online: metric_factory(ONLINE, repo, runner),
+            busy: metric_factory(BUSY, repo, runner),
+            offline_duration: metric_factory(OFFLINE_DURATION, repo, runner),
+            transitions: IntCounter::with_opts(
+                TRANSITIONS
+                    .opts()
+                    .const_label("repo", repo)
+                    .const_label("runner", runner),
+            )
+            .unwrap(),
+            deregistered: metric_factory(DEREGISTERED, repo, runner),
+            last_online: None,
+            offline_since: 0,
+            deregistered_at: 0,
+        }
+    }
 
+    fn update(&mut self, online: bool, busy: bool, now: i64) {
+        if self
+            .last_online
+            .is_some_and(|was_online| was_online != online)
+        {
+            self.transitions.inc();
+        }
+        self.last_online = Some(online);
+
+        self.offline_since = match (online, self.offline_since) {
+            (true, _) => 0,
+            (false, 0) => now,
+            (false, since) => since,
+        };
+        self.deregistered_at = 0;
+
+        self.online.set(if online { 1 } else { 0 });
+        self.busy.set(if busy { 1 } else { 0 });
+        self.deregistered.set(0);
+    }
+
+    fn deregister(&mut self, now: i64) {
+        if self.deregistered_at == 0 {
+            self.deregistered_at = now;
+        }
+        self.deregistered.set(1);
+    }
+
+    fn update_offline_duration(&self, now: i64) {
+        self.offline_duration.set(match self.offline_since {
+            0 => 0,
+            since => (now - since).max(0),
+        });
+    }
+
+    fn snapshot(&self, repo: &str, runner: &str) -> RunnerSnapshot {
+        RunnerSnapshot {
+            repo: repo.to_string(),
+            runner: runner.to_string(),
+            online: self.online.get(),
+            busy: self.busy.get(),
+            offline_since: self.offline_since,
+            transitions: self.transitions.get(),
+            deregistered_at: self.deregistered_at,
+        }
+    }
+
+    fn restore(&mut self, snapshot: &RunnerSnapshot) {
+        self.online.set(snapshot.online);
+        self.busy.set(snapshot.busy);
+        self.last_online = Some(snapshot.online == 1);
+        self.offline_since = snapshot.offline_since;
+        self.transitions.inc_by(snapshot.transitions);
+        self.deregistered_at = snapshot.deregistered_at;
+        self.deregistered
+            .set(if snapshot.deregistered_at > 0 { 1 } else { 0 });
+    }
+}
+
+impl Refresh for GithubRunners {
+    fn refresh(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(self.update_stats())
+    }
+
+    fn snapshot(&self) -> Result<Option<Value>, Error> {
+        let guard = self.metrics.read().unwrap();
+        let runners: Vec<RunnerSnapshot> = guard
+            .iter()
+            .map(|((repo, runner), metrics)| metrics.snapshot(repo, runner))
+            .collect();
+        Ok(Some(serde_json::to_value(runners)?))
+    }
+
+    fn restore(&self, snapshot: Value) -> Result<(), Error> {
+        let runners: Vec<RunnerSnapshot> = serde_json::from_value(snapshot)?;
+        let mut guard = self.metrics.write().unwrap();
+        // skip repos that are no longer tracked
+        for runner in runners.iter().filter(|r| self.repos.contains(&r.repo)) {
+            let metrics = guard.refresh_with((runner.repo.clone(), runner.runner.clone()), || {
+                RunnerMetrics::new(&runner.repo, &runner.runner)
+            });
+            metrics.restore(runner);
+        }
         Ok(())
     }
 }
 
 impl Collector for GithubRunners {
     fn desc(&self) -> Vec<&Desc> {
-        vec![&self.desc]
+        self.descs.iter().collect()
     }
 
     fn collect(&self) -> Vec<MetricFamily> {
-        self.metrics.read().map_or_else(
+        self.metrics.write().map_or_else(
             |e| {
                 error!("Unable to collect: {:#?}", e);
                 Vec::with_capacity(0)
             },
-            |guard| {
-                guard.iter().fold(Vec::new(), |mut acc, item| {
-                    acc.extend(item.collect());
+            |mut guard| {
+                guard.expire();
+                let now = unix_now();
+                guard.values().fold(Vec::new(), |mut acc, item| {
+                    // only the deregistration is reported once a runner is gone
+                    if item.deregistered_at == 0 {
+                        item.update_offline_duration(now);
+                        acc.extend(item.online.collect());
+                        acc.extend(item.busy.collect());
+                        acc.extend(item.offline_duration.collect());
+                        acc.extend(item.transitions.collect());
+                    }
+                    acc.extend(item.deregistered.collect());
                     acc
                 })
             },
@@ -160,10 +362,60 @@ impl Collector for GithubRunners {
     }
 }
 
-fn guard_rate_limited(response: &Response) -> Result<&Response> {
+pub(crate) async fn check_config(config: &Config, http: &HttpClient, report: &mut Report) {
+    report.section("GitHub Actions runners (GITHUB_TOKEN, RUNNERS_REPOS)");
+
+    let token = match config.github_token.get() {
+        Ok(token) => token,
+        Err(e) => {
+            report.failure("GITHUB_TOKEN", &e);
+            return;
+        }
+    };
+    match verify_token(http, &config.github_api_url, &token).await {
+        Ok(info) => {
+            info.report(report, "GITHUB_TOKEN");
+            if let Some(scopes) = &info.scopes {
+                if !scopes.iter().any(|scope| scope == "repo") {
+                    report.error("GITHUB_TOKEN", "token is missing the repo scope");
+                }
+            }
+        }
+        Err(e) => report.failure("GITHUB_TOKEN", &e),
+    }
+
+    for repo in config.gha_runners_repos.split(',').map(str::trim) {
+        if repo.split('/').count() != 2 || repo.split('/').any(str::is_empty) {
+            report.error(repo, "expected a repository in the owner/repo format");
+            continue;
+        }
+
+        let url = String::from(GH_RUNNERS_ENDPOINT)
+            .replace("{api_url}", &config.github_api_url)
+            .replace("{owner_repo}", repo);
+        let result = async {
+            http.send(http.get(&url).headers(default_headers(&token)))
+                .await?
+                .error_for_status()
+                .context("unable to list the repository's runners")?
+                .json::<ApiResponse>()
+                .await
+                .context("unable to deserialize the repository's runners")
+        }
+        .await;
+
+        match result {
+            Ok(resp) => report.ok(repo, format!("{} runner(s) registered", resp.total_count)),
+            Err(e) => report.failure(repo, &e),
+        }
+    }
+}
+
+pub(super) fn guard_rate_limited(response: &Response) -> Result<&Response> {
+    // error responses (e.g. 5xx) don't always include the rate limit headers
     let rate_limited = match response.headers().get("x-ratelimit-remaining") {
         Some(rl) => rl.to_str()?.parse::<usize>()? == 0,
-        None => unreachable!(),
+        None => false,
     };
 
     if rate_limited {
@@ -175,7 +427,7 @@ fn guard_rate_limited(response: &Response) -> Result<&Response> {
     Ok(response)
 }
 
-fn next_uri(header: Option<&HeaderValue>) -> Option<String> {
+pub(super) fn next_uri(header: Option<&HeaderValue>) -> Option<String> {
     if let Some(header) = header {
         return match header.to_str() {
             Ok(header_str) => match parse_link_header::parse(header_str) {
@@ -191,18 +443,51 @@ fn next_uri(header: Option<&HeaderValue>) -> Option<String> {
     None
 }
 
-fn metric_factory<S: Into<String>>(
-    name: S,
-    help: S,
-    ns: S,
-    repo: S,
-    runner: S,
-) -> GenericGauge<AtomicI64> {
+fn metric_factory(def: MetricDef, repo: &str, runner: &str) -> IntGauge {
     IntGauge::with_opts(
-        Opts::new(name, help)
-            .namespace(ns)
+        def.opts()
             .const_label("repo", repo)
             .const_label("runner", runner),
     )
     .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::RunnerMetrics;
+
+    #[test]
+    fn offline_duration_and_transitions() {
+        let mut runner = RunnerMetrics::new("rust-lang/rust", "aws-1");
+        runner.update(false, false, 1_000);
+        runner.update_offline_duration(1_300);
+        assert_eq!(300, runner.offline_duration.get());
+        // the first state seen isn't a transition
+        assert_eq!(0, runner.transitions.get());
+
+        runner.update(false, false, 1_200);
+        runner.update(true, true, 1_400);
+        runner.update_offline_duration(1_500);
+        assert_eq!(0, runner.offline_duration.get());
+        assert_eq!(1, runner.transitions.get());
+
+        runner.update(false, false, 1_600);
+        runner.update(true, false, 1_800);
+        assert_eq!(3, runner.transitions.get());
+    }
+
+    #[test]
+    fn deregistered_until_seen_again() {
+        let mut runner = RunnerMetrics::new("rust-lang/rust", "aws-1");
+        runner.update(true, false, 1_000);
+        runner.deregister(1_200);
+        runner.deregister(1_400);
+        assert_eq!(1_200, runner.deregistered_at);
+        assert_eq!(1, runner.deregistered.get());
+
+        runner.update(true, false, 1_600);
+        assert_eq!(0, runner.deregistered_at);
+        assert_eq!(0, runner.deregistered.get());
+        assert_eq!(0, runner.transitions.get());
+    }
+}
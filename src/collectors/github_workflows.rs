@@ -0,0 +1,487 @@
+use super::github_runners::guard_rate_limited;
+use super::{default_headers, descs, unix_now, AlertDef, MetricDef, PanelDef, Refresh};
+use crate::check::Report;
+use crate::{Config, HttpClient, Secret, SecretSource};
+use anyhow::{Context, Error, Result};
+use chrono::{DateTime, Utc};
+use futures::future::{join_all, BoxFuture};
+use log::{debug, error, warn};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::IntGauge;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+
+const WORKFLOW_RUNS_ENDPOINT: &str =
+    "{api_url}/repos/{owner_repo}/actions/workflows/{workflow}/runs";
+
+const RUNS: MetricDef = MetricDef::gauge(
+    "gha_workflow_runs",
+    "Number of recent completed runs of the workflow on the branch, by conclusion",
+    &["repo", "workflow", "branch", "conclusion"],
+);
+const CONSECUTIVE_FAILURES: MetricDef = MetricDef::gauge(
+    "gha_workflow_consecutive_failures",
+    "Number of runs of the workflow on the branch that failed since the last successful one",
+    &["repo", "workflow", "branch"],
+);
+const LAST_SUCCESS_DURATION: MetricDef = MetricDef::gauge(
+    "gha_workflow_last_success_duration_seconds",
+    "Time taken by the last successful run of the workflow on the branch",
+    &["repo", "workflow", "branch"],
+);
+const LAST_SUCCESS_AGE: MetricDef = MetricDef::gauge(
+    "gha_workflow_last_success_age_seconds",
+    "Time elapsed since the last successful run of the workflow on the branch finished",
+    &["repo", "workflow", "branch"],
+);
+
+pub(crate) const METRICS: &[MetricDef] = &[
+    RUNS,
+    CONSECUTIVE_FAILURES,
+    LAST_SUCCESS_DURATION,
+    LAST_SUCCESS_AGE,
+];
+
+pub(crate) const ALERTS: &[AlertDef] = &[AlertDef {
+    name: "GitHubWorkflowFailing",
+    expr: "{gha_workflow_consecutive_failures} >= 3",
+    for_: "0m",
+    severity: "warning",
+    summary: "The last {{ $value }} runs of {{ $labels.workflow }} on {{ $labels.repo }}@{{ $labels.branch }} failed",
+}];
+
+pub(crate) const PANELS: &[PanelDef] = &[
+    PanelDef {
+        title: "Workflow success rate",
+        expr: "{gha_workflow_runs}{conclusion=\"success\"} / ignoring (conclusion) sum without (conclusion) ({gha_workflow_runs})",
+        legend: "{{repo}} {{workflow}}@{{branch}}",
+        unit: "percentunit",
+    },
+    PanelDef {
+        title: "Time since the last green run",
+        expr: "{gha_workflow_last_success_age_seconds}",
+        legend: "{{repo}} {{workflow}}@{{branch}}",
+        unit: "s",
+    },
+];
+
+// conclusions of completed runs, see the GitHub documentation of check runs
+const CONCLUSIONS: &[&str] = &[
+    "success",
+    "failure",
+    "cancelled",
+    "timed_out",
+    "startup_failure",
+    "action_required",
+    "neutral",
+    "skipped",
+    "stale",
+];
+
+// conclusions counted as failures, the others (e.g. cancelled) are ignored
+const FAILED_CONCLUSIONS: &[&str] = &["failure", "timed_out", "startup_failure"];
+
+/// A workflow to track on a branch, parsed from `owner/repo/workflow@branch`,
+/// e.g. `rust-lang/rust/ci.yml@master`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Workflow {
+    repo: String,
+    // file name or id of the workflow
+    workflow: String,
+    branch: String,
+}
+
+impl FromStr for Workflow {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = || {
+            let (path, branch) = s.split_once('@')?;
+            let (repo, workflow) = path.rsplit_once('/')?;
+            let valid_repo = repo.split('/').count() == 2 && !repo.split('/').any(str::is_empty);
+            if !valid_repo || workflow.is_empty() || branch.is_empty() {
+                return None;
+            }
+            Some(Self {
+                repo: repo.to_string(),
+                workflow: workflow.to_string(),
+                branch: branch.to_string(),
+            })
+        };
+        parse().with_context(|| format!("expected `owner/repo/workflow@branch`, got {}", s))
+    }
+}
+
+fn parse_workflows(workflows: &str) -> Result<Vec<Workflow>> {
+    workflows
+        .split(',')
+        .map(str::trim)
+        .filter(|workflow| !workflow.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct WorkflowRuns {
+    workflow_runs: Vec<WorkflowRun>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct WorkflowRun {
+    conclusion: Option<String>,
+    run_started_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+/// Statistics of the recent runs of a workflow, newest first.
+#[derive(Debug, PartialEq, Eq)]
+struct RunStats {
+    // one per conclusion, in order
+    conclusions: Vec<i64>,
+    consecutive_failures: i64,
+}
+
+impl RunStats {
+    fn new(runs: &[WorkflowRun]) -> Self {
+        let mut conclusions = vec![0; CONCLUSIONS.len()];
+        for run in runs.iter() {
+            let conclusion = run.conclusion.as_deref().unwrap_or_default();
+            match CONCLUSIONS.iter().position(|c| *c == conclusion) {
+                Some(index) => conclusions[index] += 1,
+                None => warn!("Unknown workflow run conclusion {:?}", conclusion),
+            }
+        }
+
+        let consecutive_failures = runs
+            .iter()
+            .filter_map(|run| run.conclusion.as_deref())
+            .take_while(|conclusion| *conclusion != "success")
+            .filter(|conclusion| FAILED_CONCLUSIONS.contains(conclusion))
+            .count() as i64;
+
+        Self {
+            conclusions,
+            consecutive_failures,
+        }
+    }
+}
+
+// the runs are listed in a single page, which holds at most 100 items
+const MAX_RUNS: usize = 100;
+
+fn check_runs(runs: usize) -> Result<()> {
+    anyhow::ensure!(
+        (1..=MAX_RUNS).contains(&runs),
+        "the number of workflow runs to inspect must be between 1 and {}, got {}",
+        MAX_RUNS,
+        runs
+    );
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct GitHubWorkflows {
+    token: SecretSource,
+    workflows: Arc<Vec<WorkflowMetrics>>,
+    // recent completed runs to inspect per workflow
+    runs: usize,
+    descs: Vec<Desc>,
+    api_url: String,
+    http: HttpClient,
+}
+
+struct WorkflowMetrics {
+    workflow: Workflow,
+    // one per conclusion, in order
+    runs: Vec<IntGauge>,
+    consecutive_failures: IntGauge,
+    last_success_duration: IntGauge,
+    last_success_age: IntGauge,
+    // unix time the last successful run finished, 0 if unknown
+    last_success: AtomicI64,
+}
+
+impl WorkflowMetrics {
+    fn new(workflow: Workflow) -> Self {
+        let opts = |def: MetricDef| {
+            def.opts()
+                .const_label("repo", &workflow.repo)
+                .const_label("workflow", &workflow.workflow)
+                .const_label("branch", &workflow.branch)
+        };
+        Self {
+            runs: CONCLUSIONS
+                .iter()
+                .map(|conclusion| {
+                    IntGauge::with_opts(opts(RUNS).const_label("conclusion", *conclusion)).unwrap()
+                })
+                .collect(),
+            consecutive_failures: IntGauge::with_opts(opts(CONSECUTIVE_FAILURES)).unwrap(),
+            last_success_duration: IntGauge::with_opts(opts(LAST_SUCCESS_DURATION)).unwrap(),
+            last_success_age: IntGauge::with_opts(opts(LAST_SUCCESS_AGE)).unwrap(),
+            last_success: AtomicI64::new(0),
+            workflow,
+        }
+    }
+}
+
+impl GitHubWorkflows {
+    pub fn new(config: &Config, http: HttpClient) -> Result<Self> {
+        check_runs(config.gha_workflow_runs)?;
+        let workflows = parse_workflows(&config.gha_workflows)?
+            .into_iter()
+            .map(WorkflowMetrics::new)
+            .collect();
+
+        Ok(Self {
+            token: config.github_token.clone(),
+            workflows: Arc::new(workflows),
+            runs: config.gha_workflow_runs,
+            descs: descs(METRICS),
+            api_url: config.github_api_url.clone(),
+            http,
+        })
+    }
+
+    async fn update_stats(&self) -> Result<(), Error> {
+        debug!("Updating workflow run stats");
+
+        let token = self.token.get()?;
+        let results = join_all(
+            self.workflows
+                .iter()
+                .map(|metrics| self.update_workflow_stats(&token, metrics)),
+        )
+        .await;
+
+        let mut failed = 0;
+        for (metrics, result) in self.workflows.iter().zip(results) {
+            if let Err(e) = result {
+                let workflow = &metrics.workflow;
+                error!(
+                    "Unable to update the runs of {} on {}@{}: {:?}",
+                    workflow.workflow, workflow.repo, workflow.branch, e
+                );
+                failed += 1;
+            }
+        }
+
+        if failed > 0 {
+            anyhow::bail!(
+                "failed to update the runs of {} of {} workflows",
+                failed,
+                self.workflows.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn update_workflow_stats(&self, token: &Secret, metrics: &WorkflowMetrics) -> Result<()> {
+        let workflow = &metrics.workflow;
+        let runs = list_runs(
+            &self.http,
+            &self.api_url,
+            token,
+            workflow,
+            "completed",
+            self.runs,
+        )
+        .await?;
+        // the last successful run can be older than the inspected runs
+        let last_success =
+            list_runs(&self.http, &self.api_url, token, workflow, "success", 1).await?;
+
+        let stats = RunStats::new(&runs);
+        for (gauge, count) in metrics.runs.iter().zip(stats.conclusions.iter()) {
+            gauge.set(*count);
+        }
+        metrics.consecutive_failures.set(stats.consecutive_failures);
+        match last_success.first() {
+            Some(run) => {
+                metrics
+                    .last_success_duration
+                    .set((run.updated_at - run.run_started_at).num_seconds());
+                metrics
+                    .last_success
+                    .store(run.updated_at.timestamp(), Ordering::SeqCst);
+            }
+            // e.g. the branch was renamed, which hides the age and duration
+            None => metrics.last_success.store(0, Ordering::SeqCst),
+        }
+
+        Ok(())
+    }
+}
+
+async fn list_runs(
+    http: &HttpClient,
+    api_url: &str,
+    token: &Secret,
+    workflow: &Workflow,
+    status: &str,
+    count: usize,
+) -> Result<Vec<WorkflowRun>> {
+    let url = WORKFLOW_RUNS_ENDPOINT
+        .replace("{api_url}", api_url)
+        .replace("{owner_repo}", &workflow.repo)
+        .replace("{workflow}", &workflow.workflow);
+    let per_page = count.to_string();
+    let request = http.get(&url).headers(default_headers(token)).query(&[
+        ("branch", workflow.branch.as_str()),
+        ("status", status),
+        ("per_page", &per_page),
+    ]);
+    let response = http.send(request).await?;
+    guard_rate_limited(&response)?;
+    let runs = response
+        .error_for_status()?
+        .json::<WorkflowRuns>()
+        .await
+        .context("unable to deserialize the workflow runs")?;
+    Ok(runs.workflow_runs)
+}
+
+impl Refresh for GitHubWorkflows {
+    fn refresh(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(self.update_stats())
+    }
+}
+
+impl Collector for GitHubWorkflows {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let now = unix_now();
+
+        let mut metrics = Vec::new();
+        for workflow in self.workflows.iter() {
+            for runs in workflow.runs.iter() {
+                metrics.extend(runs.collect());
+            }
+            metrics.extend(workflow.consecutive_failures.collect());
+            // there's nothing to report until a successful run was found
+            let last_success = workflow.last_success.load(Ordering::SeqCst);
+            if last_success > 0 {
+                workflow.last_success_age.set((now - last_success).max(0));
+                metrics.extend(workflow.last_success_duration.collect());
+                metrics.extend(workflow.last_success_age.collect());
+            }
+        }
+        metrics
+    }
+}
+
+pub(crate) async fn check_config(config: &Config, http: &HttpClient, report: &mut Report) {
+    report.section("GitHub Actions workflows (GHA_WORKFLOWS, GITHUB_TOKEN)");
+
+    let workflows = match parse_workflows(&config.gha_workflows) {
+        Ok(workflows) if workflows.is_empty() => {
+            report.ok("GHA_WORKFLOWS", "no workflows, collector disabled");
+            return;
+        }
+        Ok(workflows) => workflows,
+        Err(e) => {
+            report.failure("GHA_WORKFLOWS", &e);
+            return;
+        }
+    };
+    if let Err(e) = check_runs(config.gha_workflow_runs) {
+        report.failure("GHA_WORKFLOW_RUNS", &e);
+        return;
+    }
+
+    let token = match config.github_token.get() {
+        Ok(token) => token,
+        Err(e) => {
+            report.failure("GITHUB_TOKEN", &e);
+            return;
+        }
+    };
+    for workflow in workflows.iter() {
+        let subject = format!(
+            "{}/{}@{}",
+            workflow.repo, workflow.workflow, workflow.branch
+        );
+        match list_runs(
+            http,
+            &config.github_api_url,
+            &token,
+            workflow,
+            "completed",
+            1,
+        )
+        .await
+        {
+            Ok(runs) if runs.is_empty() => report.warning(subject, "no completed runs"),
+            Ok(_) => report.ok(subject, "runs listed"),
+            Err(e) => report.failure(subject, &e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_runs, parse_workflows, RunStats, Workflow, WorkflowRun, CONCLUSIONS};
+
+    #[test]
+    fn runs_fit_in_one_page() {
+        assert!(check_runs(1).is_ok());
+        assert!(check_runs(100).is_ok());
+        assert!(check_runs(0).is_err());
+        assert!(check_runs(101).is_err());
+    }
+
+    #[test]
+    fn parse_workflow_triples() {
+        assert_eq!(
+            vec![
+                Workflow {
+                    repo: "rust-lang/rust".into(),
+                    workflow: "ci.yml".into(),
+                    branch: "master".into(),
+                },
+                Workflow {
+                    repo: "rust-lang/cargo".into(),
+                    workflow: "1234".into(),
+                    branch: "rust-1.79.0/beta".into(),
+                },
+            ],
+            parse_workflows("rust-lang/rust/ci.yml@master, rust-lang/cargo/1234@rust-1.79.0/beta,")
+                .unwrap()
+        );
+        assert!(parse_workflows("rust-lang/rust@master").is_err());
+        assert!(parse_workflows("rust-lang/rust/ci.yml").is_err());
+        assert!(parse_workflows("rust/ci.yml@master").is_err());
+    }
+
+    #[test]
+    fn consecutive_failures_ignore_cancelled_runs() {
+        let run = |conclusion: &str| WorkflowRun {
+            conclusion: Some(conclusion.to_string()),
+            run_started_at: "2024-06-21T12:00:00Z".parse().unwrap(),
+            updated_at: "2024-06-21T14:00:00Z".parse().unwrap(),
+        };
+        let runs = [
+            run("failure"),
+            run("cancelled"),
+            run("timed_out"),
+            run("success"),
+            run("failure"),
+        ];
+
+        let stats = RunStats::new(&runs);
+        assert_eq!(2, stats.consecutive_failures);
+        let count = |conclusion: &str| {
+            stats.conclusions[CONCLUSIONS.iter().position(|c| *c == conclusion).unwrap()]
+        };
+        assert_eq!(1, count("success"));
+        assert_eq!(2, count("failure"));
+        assert_eq!(1, count("cancelled"));
+        assert_eq!(1, count("timed_out"));
+    }
+}
@@ -0,0 +1,334 @@
+use super::{descs, AlertDef, MetricDef, PanelDef, Refresh};
+use crate::check::Report;
+use crate::{Config, HttpClient};
+use anyhow::{Context, Error, Result};
+use futures::future::{join_all, BoxFuture};
+use log::{debug, warn};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{Gauge, IntGauge};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const UP: MetricDef = MetricDef::gauge(
+    "http_probe_up",
+    "Whether the target answered with a successful status and passed every check",
+    &["target"],
+);
+const STATUS_CODE: MetricDef = MetricDef::gauge(
+    "http_probe_status_code",
+    "HTTP status code of the last probe, 0 if the request failed",
+    &["target"],
+);
+const DURATION: MetricDef = MetricDef::gauge(
+    "http_probe_duration_seconds",
+    "Time taken by the last probe, including reading the body",
+    &["target"],
+);
+const CHECK: MetricDef = MetricDef::gauge(
+    "http_probe_check_success",
+    "Whether the body or header check of the target passed in the last probe",
+    &["target", "check"],
+);
+
+pub(crate) const METRICS: &[MetricDef] = &[UP, STATUS_CODE, DURATION, CHECK];
+
+pub(crate) const ALERTS: &[AlertDef] = &[AlertDef {
+    name: "HttpProbeDown",
+    expr: "{http_probe_up} == 0",
+    for_: "5m",
+    severity: "critical",
+    summary: "{{ $labels.target }} is down",
+}];
+
+pub(crate) const PANELS: &[PanelDef] = &[
+    PanelDef {
+        title: "Probe latency",
+        expr: "{http_probe_duration_seconds}",
+        legend: "{{target}}",
+        unit: "s",
+    },
+    PanelDef {
+        title: "Targets up",
+        expr: "{http_probe_up}",
+        legend: "{{target}}",
+        unit: "short",
+    },
+];
+
+/// A probed URL along with the checks its response must pass, parsed from
+/// e.g. `https://crates.io/api/v1/summary body=num_crates header=content-type:json`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Target {
+    url: String,
+    checks: Vec<Check>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Check {
+    // the body contains the string
+    Body(String),
+    // the header is present and contains the string
+    Header(String, String),
+}
+
+impl FromStr for Target {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let url = parts.next().context("empty probe target")?;
+        anyhow::ensure!(
+            url.starts_with("http://") || url.starts_with("https://"),
+            "probe target {} is not an http(s) url",
+            url
+        );
+
+        let checks = parts
+            .map(|check| match check.split_once('=') {
+                Some(("body", expected)) => Ok(Check::Body(expected.to_string())),
+                Some(("header", header)) => {
+                    let (name, expected) = header
+                        .split_once(':')
+                        .with_context(|| format!("expected header=name:value, got {}", check))?;
+                    Ok(Check::Header(name.to_lowercase(), expected.to_string()))
+                }
+                _ => anyhow::bail!("unknown check {} of probe target {}", check, url),
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            url: url.to_string(),
+            checks,
+        })
+    }
+}
+
+impl Check {
+    fn label(&self) -> String {
+        match self {
+            Check::Body(expected) => format!("body={}", expected),
+            Check::Header(name, expected) => format!("header={}:{}", name, expected),
+        }
+    }
+}
+
+fn parse_targets(targets: &str) -> Result<Vec<Target>> {
+    targets
+        .split(',')
+        .filter(|target| !target.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Outcome of probing a target.
+struct Probe {
+    status: Option<u16>,
+    duration: Duration,
+    // result of every check of the target, in order
+    checks: Vec<bool>,
+}
+
+impl Probe {
+    fn up(&self) -> bool {
+        matches!(self.status, Some(status) if (200..300).contains(&status))
+            && self.checks.iter().all(|passed| *passed)
+    }
+}
+
+#[derive(Clone)]
+pub struct HttpProbe {
+    targets: Arc<Vec<TargetMetrics>>,
+    timeout: Duration,
+    descs: Vec<Desc>,
+    http: HttpClient,
+}
+
+struct TargetMetrics {
+    target: Target,
+    up: IntGauge,
+    status_code: IntGauge,
+    duration: Gauge,
+    checks: Vec<IntGauge>,
+}
+
+impl HttpProbe {
+    pub fn new(config: &Config, http: HttpClient) -> Result<Self> {
+        let targets = parse_targets(&config.http_probe_targets)?
+            .into_iter()
+            .map(|target| {
+                let opts = |def: MetricDef| def.opts().const_label("target", &target.url);
+                TargetMetrics {
+                    up: IntGauge::with_opts(opts(UP)).unwrap(),
+                    status_code: IntGauge::with_opts(opts(STATUS_CODE)).unwrap(),
+                    duration: Gauge::with_opts(opts(DURATION)).unwrap(),
+                    checks: target
+                        .checks
+                        .iter()
+                        .map(|check| {
+                            IntGauge::with_opts(opts(CHECK).const_label("check", check.label()))
+                                .unwrap()
+                        })
+                        .collect(),
+                    target,
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            targets: Arc::new(targets),
+            timeout: Duration::from_secs(config.http_probe_timeout),
+            descs: descs(METRICS),
+            http,
+        })
+    }
+
+    async fn update_stats(&self) -> Result<(), Error> {
+        debug!("Probing {} http targets", self.targets.len());
+
+        let probes = join_all(
+            self.targets
+                .iter()
+                .map(|t| probe(&self.http, &t.target, self.timeout)),
+        )
+        .await;
+
+        for (metrics, probe) in self.targets.iter().zip(probes) {
+            metrics.up.set(if probe.up() { 1 } else { 0 });
+            metrics.status_code.set(probe.status.map_or(0, i64::from));
+            metrics.duration.set(probe.duration.as_secs_f64());
+            for (gauge, passed) in metrics.checks.iter().zip(probe.checks.iter()) {
+                gauge.set(if *passed { 1 } else { 0 });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn probe(http: &HttpClient, target: &Target, timeout: Duration) -> Probe {
+    let start = Instant::now();
+    let result = async {
+        let response = http.send(http.get(&target.url).timeout(timeout)).await?;
+        let status = response.status().as_u16();
+        let headers = response.headers().clone();
+        let body = response.text().await?;
+
+        let checks = target
+            .checks
+            .iter()
+            .map(|check| match check {
+                Check::Body(expected) => body.contains(expected.as_str()),
+                Check::Header(name, expected) => headers
+                    .get_all(name.as_str())
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .any(|value| value.contains(expected.as_str())),
+            })
+            .collect();
+        Ok::<_, Error>((status, checks))
+    }
+    .await;
+
+    match result {
+        Ok((status, checks)) => Probe {
+            status: Some(status),
+            duration: start.elapsed(),
+            checks,
+        },
+        Err(e) => {
+            warn!("Unable to probe {}: {:?}", target.url, e);
+            Probe {
+                status: None,
+                duration: start.elapsed(),
+                checks: vec![false; target.checks.len()],
+            }
+        }
+    }
+}
+
+impl Refresh for HttpProbe {
+    fn refresh(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(self.update_stats())
+    }
+}
+
+impl Collector for HttpProbe {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut metrics = Vec::new();
+        for target in self.targets.iter() {
+            metrics.extend(target.up.collect());
+            metrics.extend(target.status_code.collect());
+            metrics.extend(target.duration.collect());
+            for check in target.checks.iter() {
+                metrics.extend(check.collect());
+            }
+        }
+        metrics
+    }
+}
+
+pub(crate) async fn check_config(config: &Config, http: &HttpClient, report: &mut Report) {
+    report.section("HTTP probes (HTTP_PROBE_TARGETS)");
+
+    let targets = match parse_targets(&config.http_probe_targets) {
+        Ok(targets) if targets.is_empty() => {
+            report.ok("HTTP_PROBE_TARGETS", "no targets, collector disabled");
+            return;
+        }
+        Ok(targets) => targets,
+        Err(e) => {
+            report.failure("HTTP_PROBE_TARGETS", &e);
+            return;
+        }
+    };
+
+    let timeout = Duration::from_secs(config.http_probe_timeout);
+    for target in targets.iter() {
+        let probe = probe(http, target, timeout).await;
+        let message = match probe.status {
+            Some(status) => format!("status {} in {:.2}s", status, probe.duration.as_secs_f64()),
+            None => "request failed".to_string(),
+        };
+        // a target that's down is worth knowing about, but isn't a configuration error
+        if probe.up() {
+            report.ok(&target.url, message);
+        } else {
+            report.warning(&target.url, format!("{}, the target is down", message));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_targets, Check, Target};
+
+    #[test]
+    fn parse_targets_with_checks() {
+        let targets = parse_targets(
+            "https://crates.io/api/v1/summary body=num_crates, http://localhost header=Server:nginx,",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                Target {
+                    url: "https://crates.io/api/v1/summary".into(),
+                    checks: vec![Check::Body("num_crates".into())],
+                },
+                Target {
+                    url: "http://localhost".into(),
+                    checks: vec![Check::Header("server".into(), "nginx".into())],
+                },
+            ],
+            targets
+        );
+
+        assert!(parse_targets("crates.io").is_err());
+        assert!(parse_targets("https://crates.io status=200").is_err());
+    }
+}
@@ -1,43 +1,517 @@
+mod bors_queue;
+mod crates_index;
+mod dns_records;
+mod docs_rs;
 mod github_rate_limit;
 mod github_runners;
+mod github_webhooks;
+mod github_workflows;
+mod http_probe;
+mod rust_channels;
+mod series;
+mod state;
+mod tls_certs;
 
+pub use crate::collectors::bors_queue::BorsQueue;
+pub use crate::collectors::crates_index::CratesIndex;
+pub use crate::collectors::dns_records::DnsRecords;
+pub use crate::collectors::docs_rs::DocsRs;
 pub use crate::collectors::github_rate_limit::GitHubRateLimit;
 pub use crate::collectors::github_runners::GithubRunners;
+pub use crate::collectors::github_webhooks::GitHubWebhooks;
+pub use crate::collectors::github_workflows::GitHubWorkflows;
+pub use crate::collectors::http_probe::HttpProbe;
+pub use crate::collectors::rust_channels::RustChannels;
+pub use crate::collectors::tls_certs::TlsCerts;
 
-use crate::MetricProvider;
+use crate::alerts::Alerting;
+use crate::check::Report;
+use crate::collectors::state::State;
+use crate::{Config, HttpClient, MetricProvider, Secret};
 use anyhow::{Error, Result};
-use futures::TryFutureExt;
-use log::info;
-use reqwest::header::{HeaderMap, ACCEPT, AUTHORIZATION};
-use reqwest::ClientBuilder;
-
-// register collectors for metrics gathering
-pub async fn register_collectors(p: &MetricProvider) -> Result<(), Error> {
-    let http = ClientBuilder::new()
-        .user_agent("https://github.com/rust-lang/monitorbot (infra@rust-lang.org)")
-        .build()?;
-
-    GitHubRateLimit::new(&p.config)
-        .and_then(|rl| async {
-            info!("Registering GitHubRateLimit collector");
-            p.register_collector(rl)
-        })
-        .await?;
-
-    GithubRunners::new(&p.config, http)
-        .and_then(|gr| async {
-            info!("Registering GitHubActionsRunners collector");
-            p.register_collector(gr)
-        })
-        .await
-}
-
-fn default_headers(token: &str) -> HeaderMap {
+use futures::future::{join_all, BoxFuture};
+use log::{error, info};
+use prometheus::core::Desc;
+use prometheus::{Opts, Registry};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// Every collector along with the metrics it exports.
+pub const COLLECTORS: &[CollectorInfo] = &[
+    CollectorInfo {
+        name: "github_rate_limit",
+        metrics: github_rate_limit::METRICS,
+        alerts: github_rate_limit::ALERTS,
+        panels: github_rate_limit::PANELS,
+    },
+    CollectorInfo {
+        name: "github_runners",
+        metrics: github_runners::METRICS,
+        alerts: github_runners::ALERTS,
+        panels: github_runners::PANELS,
+    },
+    CollectorInfo {
+        name: "http_probe",
+        metrics: http_probe::METRICS,
+        alerts: http_probe::ALERTS,
+        panels: http_probe::PANELS,
+    },
+    CollectorInfo {
+        name: "tls_certs",
+        metrics: tls_certs::METRICS,
+        alerts: tls_certs::ALERTS,
+        panels: tls_certs::PANELS,
+    },
+    CollectorInfo {
+        name: "dns_records",
+        metrics: dns_records::METRICS,
+        alerts: dns_records::ALERTS,
+        panels: dns_records::PANELS,
+    },
+    CollectorInfo {
+        name: "rust_channels",
+        metrics: rust_channels::METRICS,
+        alerts: rust_channels::ALERTS,
+        panels: rust_channels::PANELS,
+    },
+    CollectorInfo {
+        name: "crates_index",
+        metrics: crates_index::METRICS,
+        alerts: crates_index::ALERTS,
+        panels: crates_index::PANELS,
+    },
+    CollectorInfo {
+        name: "docs_rs",
+        metrics: docs_rs::METRICS,
+        alerts: docs_rs::ALERTS,
+        panels: docs_rs::PANELS,
+    },
+    CollectorInfo {
+        name: "bors_queue",
+        metrics: bors_queue::METRICS,
+        alerts: bors_queue::ALERTS,
+        panels: bors_queue::PANELS,
+    },
+    CollectorInfo {
+        name: "github_webhooks",
+        metrics: github_webhooks::METRICS,
+        alerts: github_webhooks::ALERTS,
+        panels: github_webhooks::PANELS,
+    },
+    CollectorInfo {
+        name: "github_workflows",
+        metrics: github_workflows::METRICS,
+        alerts: github_workflows::ALERTS,
+        panels: github_workflows::PANELS,
+    },
+];
+
+pub struct CollectorInfo {
+    /// Name of the collector, as accepted by `register_collectors`
+    pub name: &'static str,
+    pub metrics: &'static [MetricDef],
+    /// Recommended alerting rules, see `monitorbot generate`
+    pub alerts: &'static [AlertDef],
+    /// Recommended dashboard panels, see `monitorbot generate`
+    pub panels: &'static [PanelDef],
+}
+
+/// Definition of a metric exported by a collector.
+///
+/// Collectors create their metrics and descriptors from these definitions, so
+/// the metric catalog always matches what is actually exported.
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct MetricDef {
+    /// Name of the metric, without the `monitorbot_` registry prefix
+    pub name: &'static str,
+    #[serde(rename = "type")]
+    pub kind: MetricKind,
+    pub help: &'static str,
+    pub labels: &'static [&'static str],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+impl MetricDef {
+    pub const fn gauge(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+    ) -> Self {
+        Self {
+            name,
+            kind: MetricKind::Gauge,
+            help,
+            labels,
+        }
+    }
+
+    pub const fn counter(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+    ) -> Self {
+        Self {
+            name,
+            kind: MetricKind::Counter,
+            help,
+            labels,
+        }
+    }
+
+    pub(crate) fn opts(&self) -> Opts {
+        Opts::new(self.name, self.help)
+    }
+
+    pub(crate) fn desc(&self) -> Desc {
+        Desc::new(
+            self.name.to_string(),
+            self.help.to_string(),
+            self.labels.iter().map(|l| l.to_string()).collect(),
+            HashMap::new(),
+        )
+        .unwrap()
+    }
+}
+
+/// A recommended Prometheus alerting rule.
+///
+/// Metrics are referenced in `expr` as `{name}` (without the registry prefix)
+/// and expanded to their full name when generating the rules, so a renamed
+/// metric breaks the generation instead of silently breaking the alert.
+#[derive(Debug, Clone, Copy)]
+pub struct AlertDef {
+    pub name: &'static str,
+    pub expr: &'static str,
+    /// How long `expr` must hold before the alert fires, e.g. `10m`
+    pub for_: &'static str,
+    pub severity: &'static str,
+    /// Summary of the alert, which can use Prometheus templating
+    pub summary: &'static str,
+}
+
+/// A recommended Grafana dashboard panel, plotting `expr` over time.
+///
+/// Metrics are referenced in `expr` the same way as in `AlertDef`.
+#[derive(Debug, Clone, Copy)]
+pub struct PanelDef {
+    pub title: &'static str,
+    pub expr: &'static str,
+    /// Grafana legend format, e.g. `{{repo}}`
+    pub legend: &'static str,
+    /// Grafana unit, e.g. `short` or `percentunit`
+    pub unit: &'static str,
+}
+
+fn descs(metrics: &[MetricDef]) -> Vec<Desc> {
+    metrics.iter().map(MetricDef::desc).collect()
+}
+
+// current unix time in seconds, 0 if the clock is set before the epoch
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Metrics exported by the scheduler itself rather than by a collector.
+pub const SCHEDULER_METRICS: &[MetricDef] = state::METRICS;
+
+/// A collector whose data is fetched periodically instead of on every scrape.
+pub trait Refresh: Send + Sync {
+    /// Fetches fresh data for the collector's metrics.
+    ///
+    /// An error means the data couldn't be fetched. Collectors probing their
+    /// targets (e.g. `http_probe` or `dns_records`) record a failing target in
+    /// their metrics instead, as that's what they measure.
+    fn refresh(&self) -> BoxFuture<'_, Result<(), Error>>;
+
+    /// The data of the collector worth persisting across restarts, if any.
+    fn snapshot(&self) -> Result<Option<Value>, Error> {
+        Ok(None)
+    }
+
+    /// Restores data returned by `snapshot`, before the first refresh.
+    fn restore(&self, _snapshot: Value) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+struct Scheduled {
+    name: &'static str,
+    interval: Duration,
+    collector: Arc<dyn Refresh>,
+}
+
+/// The collectors registered in a `MetricProvider`, which still need to be
+/// refreshed to have any data.
+pub struct Collectors {
+    scheduled: Vec<Scheduled>,
+    registry: Registry,
+    alerting: Option<Arc<Alerting>>,
+    state: Option<Arc<State>>,
+}
+
+impl Collectors {
+    fn schedule<C>(&mut self, name: &'static str, interval: u64, collector: C)
+    where
+        C: Refresh + 'static,
+    {
+        if let Some(state) = &self.state {
+            state.restore(name, &collector);
+        }
+        self.scheduled.push(Scheduled {
+            name,
+            interval: Duration::from_secs(interval),
+            collector: Arc::new(collector),
+        });
+    }
+
+    /// Refreshes every collector in the background on its own interval,
+    /// evaluating the alert rules (if any) after every refresh. Collectors
+    /// that fail to refresh are retried less and less often, see `backoff`.
+    pub fn spawn(self) {
+        let Collectors {
+            scheduled,
+            registry,
+            alerting,
+            state,
+        } = self;
+        let evaluate = alerting.map(|alerting| spawn_alerting(alerting, registry));
+        for scheduled in scheduled {
+            let mut evaluate = evaluate.clone();
+            let state = state.clone();
+            tokio::spawn(async move {
+                let mut failures = 0;
+                loop {
+                    match scheduled.collector.refresh().await {
+                        Ok(()) => {
+                            failures = 0;
+                            if let Some(state) = &state {
+                                state.save(scheduled.name, &*scheduled.collector);
+                            }
+                            if let Some(evaluate) = &mut evaluate {
+                                // an evaluation already pending sees this refresh too
+                                let _ = evaluate.try_send(());
+                            }
+                        }
+                        Err(e) => {
+                            failures += 1;
+                            error!(
+                                "Unable to refresh {} ({} consecutive failures): {:?}",
+                                scheduled.name, failures, e
+                            );
+                        }
+                    }
+
+                    tokio::time::delay_for(backoff(scheduled.interval, failures)).await;
+                }
+            });
+        }
+    }
+
+    /// Refreshes every collector once, returning the collectors that failed.
+    pub async fn refresh_once(&self) -> Vec<(&'static str, Error)> {
+        let results = join_all(self.scheduled.iter().map(|s| s.collector.refresh())).await;
+        let mut failed = Vec::new();
+        for (scheduled, result) in self.scheduled.iter().zip(results) {
+            match result {
+                Ok(()) => {
+                    if let Some(state) = &self.state {
+                        state.save(scheduled.name, &*scheduled.collector);
+                    }
+                }
+                Err(e) => failed.push((scheduled.name, e)),
+            }
+        }
+        failed
+    }
+}
+
+// evaluates the alert rules whenever a collector refreshed, in a task of its
+// own so that slow webhooks don't hold up the refreshes
+fn spawn_alerting(alerting: Arc<Alerting>, registry: Registry) -> mpsc::Sender<()> {
+    let (sender, mut receiver) = mpsc::channel(1);
+    tokio::spawn(async move {
+        while let Some(()) = receiver.recv().await {
+            alerting.evaluate(&registry.gather()).await;
+        }
+    });
+    sender
+}
+
+/// Longest delay between two refreshes of a failing collector, unless its
+/// interval is already longer.
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+// doubles the interval after every consecutive failure, so that an unavailable
+// service isn't hammered and rate limits aren't burnt on errors
+fn backoff(interval: Duration, failures: u32) -> Duration {
+    let delay = interval.saturating_mul(2u32.saturating_pow(failures));
+    delay.min(MAX_BACKOFF.max(interval))
+}
+
+/// Registers the collectors named in `only` (or every collector if it's
+/// empty) for metrics gathering.
+pub async fn register_collectors(p: &MetricProvider, only: &[String]) -> Result<Collectors, Error> {
+    let names: Vec<&str> = COLLECTORS.iter().map(|c| c.name).collect();
+    if let Some(unknown) = only.iter().find(|name| !names.contains(&name.as_str())) {
+        anyhow::bail!(
+            "unknown collector {}, expected one of {}",
+            unknown,
+            names.join(", ")
+        );
+    }
+    let enabled = |name: &str| only.is_empty() || only.iter().any(|n| n == name);
+
+    let http = HttpClient::from_config(&p.config)?;
+    let mut collectors = Collectors {
+        scheduled: Vec::new(),
+        registry: p.register.clone(),
+        alerting: Alerting::from_config(&p.config, http.clone())?.map(Arc::new),
+        state: match &p.config.state_dir {
+            Some(dir) => Some(Arc::new(State::new(dir.clone(), &p.register)?)),
+            None => None,
+        },
+    };
+
+    if enabled("github_rate_limit") {
+        let rl = GitHubRateLimit::new(&p.config, http.clone()).await?;
+        info!("Registering GitHubRateLimit collector");
+        p.register_collector(rl.clone())?;
+        collectors.schedule(
+            "github_rate_limit",
+            p.config.gh_rate_limit_stats_cache_refresh,
+            rl,
+        );
+    }
+
+    if enabled("github_runners") {
+        let gr = GithubRunners::new(&p.config, http.clone()).await?;
+        info!("Registering GitHubActionsRunners collector");
+        p.register_collector(gr.clone())?;
+        collectors.schedule("github_runners", p.config.gha_runners_cache_refresh, gr);
+    }
+
+    if enabled("http_probe") && !p.config.http_probe_targets.trim().is_empty() {
+        let hp = HttpProbe::new(&p.config, http.clone())?;
+        info!("Registering HttpProbe collector");
+        p.register_collector(hp.clone())?;
+        collectors.schedule("http_probe", p.config.http_probe_refresh, hp);
+    }
+
+    if enabled("tls_certs") && !p.config.tls_targets.trim().is_empty() {
+        let tc = TlsCerts::new(&p.config)?;
+        info!("Registering TlsCerts collector");
+        p.register_collector(tc.clone())?;
+        collectors.schedule("tls_certs", p.config.tls_refresh, tc);
+    }
+
+    if enabled("dns_records") && !p.config.dns_records.trim().is_empty() {
+        let dr = DnsRecords::new(&p.config)?;
+        info!("Registering DnsRecords collector");
+        p.register_collector(dr.clone())?;
+        collectors.schedule("dns_records", p.config.dns_refresh, dr);
+    }
+
+    if enabled("rust_channels") && !p.config.rust_channels.trim().is_empty() {
+        let rc = RustChannels::new(&p.config, http.clone())?;
+        info!("Registering RustChannels collector");
+        p.register_collector(rc.clone())?;
+        collectors.schedule("rust_channels", p.config.rust_channels_refresh, rc);
+    }
+
+    let crates_index_enabled =
+        !p.config.crates_index_url.is_empty() || !p.config.crates_index_git_url.is_empty();
+    if enabled("crates_index") && crates_index_enabled {
+        let ci = CratesIndex::new(&p.config, http.clone())?;
+        info!("Registering CratesIndex collector");
+        p.register_collector(ci.clone())?;
+        collectors.schedule("crates_index", p.config.crates_index_refresh, ci);
+    }
+
+    if enabled("docs_rs") && !p.config.docsrs_url.is_empty() {
+        let dr = DocsRs::new(&p.config, http.clone())?;
+        info!("Registering DocsRs collector");
+        p.register_collector(dr.clone())?;
+        collectors.schedule("docs_rs", p.config.docsrs_refresh, dr);
+    }
+
+    if enabled("bors_queue") && !p.config.bors_repos.trim().is_empty() {
+        let bq = BorsQueue::new(&p.config, http.clone())?;
+        info!("Registering BorsQueue collector");
+        p.register_collector(bq.clone())?;
+        collectors.schedule("bors_queue", p.config.bors_refresh, bq);
+    }
+
+    let webhooks_enabled =
+        !p.config.webhook_repos.trim().is_empty() || !p.config.webhook_orgs.trim().is_empty();
+    if enabled("github_webhooks") && webhooks_enabled {
+        let gw = GitHubWebhooks::new(&p.config, http.clone())?;
+        info!("Registering GitHubWebhooks collector");
+        p.register_collector(gw.clone())?;
+        collectors.schedule("github_webhooks", p.config.webhook_refresh, gw);
+    }
+
+    if enabled("github_workflows") && !p.config.gha_workflows.trim().is_empty() {
+        let gw = GitHubWorkflows::new(&p.config, http)?;
+        info!("Registering GitHubWorkflows collector");
+        p.register_collector(gw.clone())?;
+        collectors.schedule("github_workflows", p.config.gha_workflows_refresh, gw);
+    }
+
+    Ok(collectors)
+}
+
+// verify the tokens and repos used by the collectors, see `monitorbot check-config`
+pub(crate) async fn check_config(config: &Config, http: &HttpClient, report: &mut Report) {
+    github_rate_limit::check_config(config, http, report).await;
+    github_runners::check_config(config, http, report).await;
+    http_probe::check_config(config, http, report).await;
+    tls_certs::check_config(config, report).await;
+    dns_records::check_config(config, report).await;
+    rust_channels::check_config(config, http, report).await;
+    crates_index::check_config(config, http, report).await;
+    docs_rs::check_config(config, http, report).await;
+    bors_queue::check_config(config, http, report).await;
+    github_webhooks::check_config(config, http, report).await;
+    github_workflows::check_config(config, http, report).await;
+}
+
+fn default_headers(token: &Secret) -> HeaderMap {
     let mut headers = HeaderMap::new();
-    headers.insert(
-        AUTHORIZATION,
-        format!("{} {}", "token", token).parse().unwrap(),
-    );
+    let mut authorization: HeaderValue = format!("{} {}", "token", token.expose_secret())
+        .parse()
+        .unwrap();
+    authorization.set_sensitive(true);
+    headers.insert(AUTHORIZATION, authorization);
     headers.insert(ACCEPT, "application/vnd.github.v3+json".parse().unwrap());
     headers
 }
+
+#[cfg(test)]
+mod tests {
+    use super::backoff;
+    use std::time::Duration;
+
+    #[test]
+    fn backoff_doubles_the_interval_up_to_a_limit() {
+        let minute = Duration::from_secs(60);
+        assert_eq!(minute, backoff(minute, 0));
+        assert_eq!(minute * 2, backoff(minute, 1));
+        assert_eq!(minute * 16, backoff(minute, 4));
+        assert_eq!(minute * 30, backoff(minute, 5));
+        assert_eq!(minute * 30, backoff(minute, 1000));
+        // longer intervals are never shortened
+        assert_eq!(minute * 60, backoff(minute * 60, 3));
+    }
+}
@@ -0,0 +1,401 @@
+use super::{descs, unix_now, AlertDef, MetricDef, PanelDef, Refresh};
+use crate::check::Report;
+use crate::{Config, HttpClient};
+use anyhow::{Context, Error, Result};
+use chrono::{NaiveDate, NaiveTime};
+use futures::future::{join_all, BoxFuture};
+use log::{debug, error};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::IntGauge;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const SCRAPE_SUCCESS: MetricDef = MetricDef::gauge(
+    "rust_channel_scrape_success",
+    "Whether the last request for the manifest of the channel succeeded",
+    &["channel"],
+);
+const MANIFEST_DATE: MetricDef = MetricDef::gauge(
+    "rust_channel_manifest_date_timestamp_seconds",
+    "Date of the current manifest of the channel, as the unix time of midnight UTC",
+    &["channel"],
+);
+const MANIFEST_AGE: MetricDef = MetricDef::gauge(
+    "rust_channel_manifest_age_seconds",
+    "Time elapsed since the date of the current manifest of the channel",
+    &["channel"],
+);
+const MISSING_COMPONENTS: MetricDef = MetricDef::gauge(
+    "rust_channel_missing_components",
+    "Number of checked components that aren't available for the target in the current manifest",
+    &["channel", "target"],
+);
+const MISSING_TARGETS: MetricDef = MetricDef::gauge(
+    "rust_channel_missing_targets",
+    "Number of checked targets missing at least one checked component in the current manifest",
+    &["channel"],
+);
+
+pub(crate) const METRICS: &[MetricDef] = &[
+    SCRAPE_SUCCESS,
+    MANIFEST_DATE,
+    MANIFEST_AGE,
+    MISSING_COMPONENTS,
+    MISSING_TARGETS,
+];
+
+pub(crate) const ALERTS: &[AlertDef] = &[
+    AlertDef {
+        name: "RustNightlyNotPublished",
+        expr: "{rust_channel_manifest_age_seconds}{channel=\"nightly\"} > 2 * 86400",
+        for_: "1h",
+        severity: "critical",
+        summary: "No nightly has been published for more than two days",
+    },
+    AlertDef {
+        name: "RustChannelMissingTargets",
+        expr: "{rust_channel_missing_targets}{channel!=\"nightly\"} > 0",
+        for_: "30m",
+        severity: "warning",
+        summary: "The {{ $labels.channel }} release is missing components on {{ $value }} targets",
+    },
+    AlertDef {
+        name: "RustChannelScrapeFailing",
+        expr: "{rust_channel_scrape_success} == 0",
+        for_: "30m",
+        severity: "warning",
+        summary: "Unable to fetch the manifest of the {{ $labels.channel }} channel",
+    },
+];
+
+pub(crate) const PANELS: &[PanelDef] = &[
+    PanelDef {
+        title: "Channel manifest age",
+        expr: "{rust_channel_manifest_age_seconds}",
+        legend: "{{channel}}",
+        unit: "s",
+    },
+    PanelDef {
+        title: "Missing components",
+        expr: "{rust_channel_missing_components} > 0",
+        legend: "{{channel}} {{target}}",
+        unit: "short",
+    },
+];
+
+/// The parts of a channel manifest (e.g. `channel-rust-nightly.toml`) the
+/// collector looks at.
+#[derive(Debug, serde::Deserialize)]
+struct Manifest {
+    date: String,
+    #[serde(default)]
+    pkg: HashMap<String, Package>,
+    // old component names along with their current package, e.g. `rustfmt`
+    // is shipped as `rustfmt-preview`
+    #[serde(default)]
+    renames: HashMap<String, Rename>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Package {
+    #[serde(default)]
+    target: HashMap<String, PackageTarget>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct PackageTarget {
+    available: bool,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Rename {
+    to: String,
+}
+
+impl Manifest {
+    /// Unix time of midnight UTC of the manifest's date.
+    fn timestamp(&self) -> Result<i64> {
+        parse_date(&self.date).with_context(|| format!("invalid manifest date {}", self.date))
+    }
+
+    /// The components that aren't available for the target.
+    fn missing_components<'a>(&self, target: &str, components: &'a [String]) -> Vec<&'a str> {
+        components
+            .iter()
+            .filter(|component| {
+                let name = self
+                    .renames
+                    .get(component.as_str())
+                    .map_or(component.as_str(), |rename| rename.to.as_str());
+                let available = self.pkg.get(name).and_then(|pkg| {
+                    // target independent packages like rust-src are listed under `*`
+                    pkg.target.get(target).or_else(|| pkg.target.get("*"))
+                });
+                !matches!(available, Some(PackageTarget { available: true }))
+            })
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+// parses a `YYYY-MM-DD` date into the unix time of its midnight UTC
+fn parse_date(date: &str) -> Result<i64> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")?;
+    Ok(date.and_time(NaiveTime::MIN).and_utc().timestamp())
+}
+
+fn parse_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct RustChannels {
+    channels: Arc<Vec<ChannelMetrics>>,
+    dist_url: String,
+    targets: Arc<Vec<String>>,
+    components: Arc<Vec<String>>,
+    descs: Vec<Desc>,
+    http: HttpClient,
+}
+
+struct ChannelMetrics {
+    channel: String,
+    scrape_success: IntGauge,
+    date: IntGauge,
+    age: IntGauge,
+    // one per checked target, in order
+    missing_components: Vec<IntGauge>,
+    missing_targets: IntGauge,
+}
+
+impl RustChannels {
+    pub fn new(config: &Config, http: HttpClient) -> Result<Self> {
+        let targets = parse_list(&config.rust_channels_targets);
+        anyhow::ensure!(!targets.is_empty(), "no targets to check the channels of");
+        let components = parse_list(&config.rust_channels_components);
+        anyhow::ensure!(
+            !components.is_empty(),
+            "no components to check the channels of"
+        );
+
+        let channels = parse_list(&config.rust_channels)
+            .into_iter()
+            .map(|channel| {
+                let opts = |def: MetricDef| def.opts().const_label("channel", &channel);
+                ChannelMetrics {
+                    scrape_success: IntGauge::with_opts(opts(SCRAPE_SUCCESS)).unwrap(),
+                    date: IntGauge::with_opts(opts(MANIFEST_DATE)).unwrap(),
+                    age: IntGauge::with_opts(opts(MANIFEST_AGE)).unwrap(),
+                    missing_components: targets
+                        .iter()
+                        .map(|target| {
+                            let opts = opts(MISSING_COMPONENTS).const_label("target", target);
+                            IntGauge::with_opts(opts).unwrap()
+                        })
+                        .collect(),
+                    missing_targets: IntGauge::with_opts(opts(MISSING_TARGETS)).unwrap(),
+                    channel,
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            channels: Arc::new(channels),
+            dist_url: config.rust_dist_url.clone(),
+            targets: Arc::new(targets),
+            components: Arc::new(components),
+            descs: descs(METRICS),
+            http,
+        })
+    }
+
+    async fn update_stats(&self) -> Result<(), Error> {
+        debug!("Updating {} rust channel manifests", self.channels.len());
+
+        let manifests = join_all(
+            self.channels
+                .iter()
+                .map(|c| fetch_manifest(&self.http, &self.dist_url, &c.channel)),
+        )
+        .await;
+
+        let mut failed = 0;
+        for (metrics, manifest) in self.channels.iter().zip(manifests) {
+            match manifest.and_then(|manifest| self.update_channel(metrics, &manifest)) {
+                Ok(()) => metrics.scrape_success.set(1),
+                Err(e) => {
+                    error!(
+                        "Unable to update the {} channel manifest: {:?}",
+                        metrics.channel, e
+                    );
+                    metrics.scrape_success.set(0);
+                    failed += 1;
+                }
+            }
+        }
+
+        if failed > 0 {
+            anyhow::bail!(
+                "failed to update {} of {} channel manifests",
+                failed,
+                self.channels.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    fn update_channel(&self, metrics: &ChannelMetrics, manifest: &Manifest) -> Result<()> {
+        metrics.date.set(manifest.timestamp()?);
+
+        let mut missing_targets = 0;
+        for (target, gauge) in self.targets.iter().zip(metrics.missing_components.iter()) {
+            let missing = manifest.missing_components(target, &self.components);
+            if !missing.is_empty() {
+                debug!(
+                    "The {} channel is missing {} on {}",
+                    metrics.channel,
+                    missing.join(", "),
+                    target
+                );
+                missing_targets += 1;
+            }
+            gauge.set(missing.len() as i64);
+        }
+        metrics.missing_targets.set(missing_targets);
+        Ok(())
+    }
+}
+
+async fn fetch_manifest(http: &HttpClient, dist_url: &str, channel: &str) -> Result<Manifest> {
+    let url = format!("{}/channel-rust-{}.toml", dist_url, channel);
+    let manifest = http
+        .send(http.get(&url))
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    toml::from_str(&manifest).with_context(|| format!("invalid manifest {}", url))
+}
+
+impl Refresh for RustChannels {
+    fn refresh(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(self.update_stats())
+    }
+}
+
+impl Collector for RustChannels {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let now = unix_now();
+
+        let mut metrics = Vec::new();
+        for channel in self.channels.iter() {
+            metrics.extend(channel.scrape_success.collect());
+            // there's no date until the manifest was fetched once
+            let date = channel.date.get();
+            if date > 0 {
+                channel.age.set(now - date);
+                metrics.extend(channel.date.collect());
+                metrics.extend(channel.age.collect());
+                for missing in channel.missing_components.iter() {
+                    metrics.extend(missing.collect());
+                }
+                metrics.extend(channel.missing_targets.collect());
+            }
+        }
+        metrics
+    }
+}
+
+pub(crate) async fn check_config(config: &Config, http: &HttpClient, report: &mut Report) {
+    report.section("Rust channels (RUST_CHANNELS, RUST_DIST_URL)");
+
+    let channels = parse_list(&config.rust_channels);
+    if channels.is_empty() {
+        report.ok("RUST_CHANNELS", "no channels, collector disabled");
+        return;
+    }
+    if parse_list(&config.rust_channels_targets).is_empty() {
+        report.error("RUST_CHANNELS_TARGETS", "no targets to check");
+    }
+    if parse_list(&config.rust_channels_components).is_empty() {
+        report.error("RUST_CHANNELS_COMPONENTS", "no components to check");
+    }
+
+    for channel in channels {
+        match fetch_manifest(http, &config.rust_dist_url, &channel).await {
+            Ok(manifest) => match manifest.timestamp() {
+                Ok(_) => report.ok(&channel, format!("manifest of {}", manifest.date)),
+                Err(e) => report.failure(&channel, &e),
+            },
+            Err(e) => report.failure(&channel, &e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_date, Manifest};
+
+    #[test]
+    fn parse_manifest_dates() {
+        assert_eq!(0, parse_date("1970-01-01").unwrap());
+        assert_eq!(951_782_400, parse_date("2000-02-29").unwrap());
+        assert_eq!(1_704_067_200, parse_date("2024-01-01").unwrap());
+        assert!(parse_date("2024-13-01").is_err());
+        assert!(parse_date("yesterday").is_err());
+    }
+
+    #[test]
+    fn missing_components_of_a_target() {
+        let manifest: Manifest = toml::from_str(
+            r#"
+            manifest-version = "2"
+            date = "2024-01-01"
+
+            [pkg.rustc.target.x86_64-unknown-linux-gnu]
+            available = true
+            [pkg.rustc.target.aarch64-apple-darwin]
+            available = true
+
+            [pkg.rustfmt-preview.target.x86_64-unknown-linux-gnu]
+            available = true
+            [pkg.rustfmt-preview.target.aarch64-apple-darwin]
+            available = false
+
+            [pkg.rust-src.target."*"]
+            available = true
+
+            [renames.rustfmt]
+            to = "rustfmt-preview"
+            "#,
+        )
+        .unwrap();
+
+        let components = ["rustc", "rustfmt", "rust-src", "clippy"]
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec!["clippy"],
+            manifest.missing_components("x86_64-unknown-linux-gnu", &components)
+        );
+        assert_eq!(
+            vec!["rustfmt", "clippy"],
+            manifest.missing_components("aarch64-apple-darwin", &components)
+        );
+        assert_eq!(
+            vec!["rustc", "rustfmt", "clippy"],
+            manifest.missing_components("x86_64-pc-windows-msvc", &components)
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Metric series of a collector keyed by their label values.
+///
+/// Every series remembers when it was last refreshed so that series which
+/// stop being reported (a runner that is gone, a rate limit product GitHub
+/// no longer returns, a repo we can't query anymore) are eventually dropped
+/// instead of exporting their last value forever.
+pub(crate) struct SeriesMap<K, V> {
+    // series not refreshed within this time are expired, `None` keeps them forever
+    ttl: Option<Duration>,
+    entries: HashMap<K, Series<V>>,
+}
+
+struct Series<V> {
+    value: V,
+    refreshed: Instant,
+}
+
+impl<K: Eq + Hash, V> SeriesMap<K, V> {
+    pub(crate) fn new(ttl: Option<Duration>) -> Self {
+        Self {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns the series for `key`, creating it with `create` if it doesn't
+    /// exist yet, and marks it as freshly refreshed.
+    pub(crate) fn refresh_with(&mut self, key: K, create: impl FnOnce() -> V) -> &mut V {
+        let series = self.entries.entry(key).or_insert_with(|| Series {
+            value: create(),
+            refreshed: Instant::now(),
+        });
+        series.refreshed = Instant::now();
+        &mut series.value
+    }
+
+    /// Replaces the series for `key` with `value`, marking it as freshly
+    /// refreshed. Used for series whose labels can change between refreshes.
+    pub(crate) fn refresh_to(&mut self, key: K, value: V) {
+        self.entries.insert(
+            key,
+            Series {
+                value,
+                refreshed: Instant::now(),
+            },
+        );
+    }
+
+    /// Drops every series for which `keep` returns false.
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&K, &V) -> bool) {
+        self.entries.retain(|k, series| keep(k, &series.value));
+    }
+
+    /// Drops every series that hasn't been refreshed within the ttl.
+    pub(crate) fn expire(&mut self) {
+        self.expire_at(Instant::now());
+    }
+
+    fn expire_at(&mut self, now: Instant) {
+        if let Some(ttl) = self.ttl {
+            self.entries
+                .retain(|_, series| now.saturating_duration_since(series.refreshed) <= ttl);
+        }
+    }
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.values().map(|series| &series.value)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries
+            .iter()
+            .map(|(key, series)| (key, &series.value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SeriesMap;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn expire_drops_only_old_series() {
+        let mut map = SeriesMap::new(Some(Duration::from_secs(60)));
+        map.refresh_with("old", || 1);
+        map.refresh_with("new", || 2);
+        map.entries.get_mut("new").unwrap().refreshed += Duration::from_secs(60);
+
+        map.expire_at(Instant::now() + Duration::from_secs(90));
+        assert_eq!(vec![&2], map.values().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn refresh_keeps_existing_value() {
+        let mut map = SeriesMap::new(None);
+        *map.refresh_with("key", || 1) += 1;
+        assert_eq!(2, *map.refresh_with("key", || 10));
+
+        map.expire_at(Instant::now() + Duration::from_secs(3600));
+        assert_eq!(1, map.values().count());
+    }
+}
@@ -0,0 +1,103 @@
+use super::{unix_now, MetricDef, Refresh};
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use prometheus::{IntGaugeVec, Registry};
+use serde_json::Value;
+use std::path::PathBuf;
+
+const STALE: MetricDef = MetricDef::gauge(
+    "collector_stale",
+    "Whether the collector only has data restored from its snapshot, as it didn't refresh successfully since startup",
+    &["collector"],
+);
+const LAST_SUCCESS: MetricDef = MetricDef::gauge(
+    "collector_last_success_timestamp_seconds",
+    "Time of the last successful refresh of the collector, including before a restart",
+    &["collector"],
+);
+
+pub(crate) const METRICS: &[MetricDef] = &[STALE, LAST_SUCCESS];
+
+/// Snapshots of the last good data of every collector, saved in `STATE_DIR`
+/// so that metrics survive restarts instead of being empty until the first
+/// refresh.
+pub(crate) struct State {
+    dir: PathBuf,
+    stale: IntGaugeVec,
+    last_success: IntGaugeVec,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Snapshot {
+    // unix time of the refresh the data comes from
+    timestamp: i64,
+    data: Value,
+}
+
+impl State {
+    pub(crate) fn new(dir: PathBuf, registry: &Registry) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("unable to create state directory {}", dir.display()))?;
+
+        let gauge = |def: MetricDef| IntGaugeVec::new(def.opts(), def.labels).unwrap();
+        let state = Self {
+            dir,
+            stale: gauge(STALE),
+            last_success: gauge(LAST_SUCCESS),
+        };
+        registry.register(Box::new(state.stale.clone()))?;
+        registry.register(Box::new(state.last_success.clone()))?;
+        Ok(state)
+    }
+
+    /// Restores the snapshot of a collector, which is marked stale until it
+    /// refreshes successfully. Unreadable snapshots are ignored.
+    pub(crate) fn restore(&self, name: &str, collector: &dyn Refresh) {
+        let path = self.dir.join(format!("{}.json", name));
+        let result = std::fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| Ok(serde_json::from_slice::<Snapshot>(&content)?))
+            .and_then(|snapshot| {
+                collector.restore(snapshot.data)?;
+                Ok(snapshot.timestamp)
+            });
+
+        match result {
+            Ok(timestamp) => {
+                info!("Restored {} from its snapshot of {}", name, timestamp);
+                self.stale.with_label_values(&[name]).set(1);
+                self.last_success.with_label_values(&[name]).set(timestamp);
+            }
+            Err(e) if !path.exists() => debug!("No snapshot to restore {} from: {:?}", name, e),
+            Err(e) => warn!(
+                "Unable to restore {} from {}: {:?}",
+                name,
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    /// Saves the data of a collector after it refreshed successfully.
+    pub(crate) fn save(&self, name: &str, collector: &dyn Refresh) {
+        let timestamp = unix_now();
+        self.stale.with_label_values(&[name]).set(0);
+        self.last_success.with_label_values(&[name]).set(timestamp);
+
+        let path = self.dir.join(format!("{}.json", name));
+        let result = collector.snapshot().and_then(|data| {
+            let data = match data {
+                Some(data) => data,
+                None => return Ok(()),
+            };
+            // write to a temporary file first so a crash never leaves a truncated snapshot
+            let tmp = path.with_extension("json.tmp");
+            std::fs::write(&tmp, serde_json::to_vec(&Snapshot { timestamp, data })?)?;
+            std::fs::rename(&tmp, &path)?;
+            Ok(())
+        });
+        if let Err(e) = result {
+            warn!("Unable to save the snapshot of {}: {:?}", name, e);
+        }
+    }
+}
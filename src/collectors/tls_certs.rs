@@ -0,0 +1,505 @@
+use super::series::SeriesMap;
+use super::{descs, unix_now, AlertDef, MetricDef, PanelDef, Refresh};
+use crate::check::Report;
+use crate::Config;
+use anyhow::{Context, Error, Result};
+use futures::future::{join_all, BoxFuture};
+use log::{debug, warn};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{Gauge, IntCounter, IntGauge};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use std::convert::TryFrom;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use x509_parser::extensions::GeneralName;
+
+const DAYS_UNTIL_EXPIRY: MetricDef = MetricDef::gauge(
+    "tls_cert_days_until_expiry",
+    "Days until the certificate expires, by depth in the chain (0 is the leaf certificate)",
+    &["target", "depth"],
+);
+const INFO: MetricDef = MetricDef::gauge(
+    "tls_cert_info",
+    "Subject, issuer and subject alternative names of the leaf certificate",
+    &["target", "subject", "issuer", "sans"],
+);
+const VERIFIED: MetricDef = MetricDef::gauge(
+    "tls_cert_verified",
+    "Whether the certificate chain is trusted by the Mozilla root certificates and valid for the host",
+    &["target"],
+);
+const HANDSHAKE_SUCCESS: MetricDef = MetricDef::gauge(
+    "tls_handshake_success",
+    "Whether the last TLS handshake with the target succeeded",
+    &["target"],
+);
+const HANDSHAKE_ERRORS: MetricDef = MetricDef::counter(
+    "tls_handshake_errors_total",
+    "Number of failed TLS handshakes with the target",
+    &["target"],
+);
+
+pub(crate) const METRICS: &[MetricDef] = &[
+    DAYS_UNTIL_EXPIRY,
+    INFO,
+    VERIFIED,
+    HANDSHAKE_SUCCESS,
+    HANDSHAKE_ERRORS,
+];
+
+pub(crate) const ALERTS: &[AlertDef] = &[
+    AlertDef {
+        name: "TlsCertExpiringSoon",
+        expr: "min by (target) ({tls_cert_days_until_expiry}) < 14",
+        for_: "1h",
+        severity: "warning",
+        summary: "A certificate of {{ $labels.target }} expires in less than 14 days",
+    },
+    AlertDef {
+        name: "TlsHandshakeFailing",
+        expr: "{tls_handshake_success} == 0",
+        for_: "15m",
+        severity: "warning",
+        summary: "Unable to complete a TLS handshake with {{ $labels.target }}",
+    },
+];
+
+pub(crate) const PANELS: &[PanelDef] = &[PanelDef {
+    title: "Days until certificate expiry",
+    expr: "min by (target) ({tls_cert_days_until_expiry})",
+    legend: "{{target}}",
+    unit: "d",
+}];
+
+/// A `host:port` to connect to, the port defaults to 443.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Target {
+    host: String,
+    port: u16,
+}
+
+impl FromStr for Target {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = match s.rsplit_once(':') {
+            Some((host, port)) if !host.ends_with(':') => (
+                host,
+                port.parse()
+                    .with_context(|| format!("invalid port in TLS target {}", s))?,
+            ),
+            _ => (s, 443),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        anyhow::ensure!(!host.is_empty(), "missing host in TLS target {}", s);
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl Target {
+    fn label(&self) -> String {
+        match self.host.contains(':') {
+            true => format!("[{}]:{}", self.host, self.port),
+            false => format!("{}:{}", self.host, self.port),
+        }
+    }
+}
+
+fn parse_targets(targets: &str) -> Result<Vec<Target>> {
+    targets
+        .split(',')
+        .map(str::trim)
+        .filter(|target| !target.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// What we learned about the certificates of a target.
+struct Certificates {
+    // expiry time of every certificate of the chain, leaf first
+    not_after: Vec<i64>,
+    subject: String,
+    issuer: String,
+    sans: Vec<String>,
+    verified: bool,
+}
+
+#[derive(Clone)]
+pub struct TlsCerts {
+    targets: Arc<Vec<TargetMetrics>>,
+    // metrics of the certificates, keyed by target
+    certs: Arc<Mutex<SeriesMap<String, CertMetrics>>>,
+    timeout: Duration,
+    descs: Vec<Desc>,
+}
+
+struct TargetMetrics {
+    target: Target,
+    handshake_success: IntGauge,
+    handshake_errors: IntCounter,
+}
+
+struct CertMetrics {
+    days_until_expiry: Vec<Gauge>,
+    // unix times the days until expiry are computed from on every scrape
+    not_after: Vec<i64>,
+    info: IntGauge,
+    verified: IntGauge,
+}
+
+impl CertMetrics {
+    fn new(target: &str, certs: &Certificates) -> Self {
+        let days_until_expiry = (0..certs.not_after.len())
+            .map(|depth| {
+                Gauge::with_opts(
+                    DAYS_UNTIL_EXPIRY
+                        .opts()
+                        .const_label("target", target)
+                        .const_label("depth", depth.to_string()),
+                )
+                .unwrap()
+            })
+            .collect();
+
+        let info = IntGauge::with_opts(
+            INFO.opts()
+                .const_label("target", target)
+                .const_label("subject", &certs.subject)
+                .const_label("issuer", &certs.issuer)
+                .const_label("sans", certs.sans.join(",")),
+        )
+        .unwrap();
+        info.set(1);
+        let verified = IntGauge::with_opts(VERIFIED.opts().const_label("target", target)).unwrap();
+        verified.set(if certs.verified { 1 } else { 0 });
+
+        Self {
+            days_until_expiry,
+            not_after: certs.not_after.clone(),
+            info,
+            verified,
+        }
+    }
+}
+
+impl TlsCerts {
+    pub fn new(config: &Config) -> Result<Self> {
+        let targets = parse_targets(&config.tls_targets)?
+            .into_iter()
+            .map(|target| {
+                let label = target.label();
+                TargetMetrics {
+                    handshake_success: IntGauge::with_opts(
+                        HANDSHAKE_SUCCESS.opts().const_label("target", &label),
+                    )
+                    .unwrap(),
+                    handshake_errors: IntCounter::with_opts(
+                        HANDSHAKE_ERRORS.opts().const_label("target", &label),
+                    )
+                    .unwrap(),
+                    target,
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            targets: Arc::new(targets),
+            certs: Arc::new(Mutex::new(SeriesMap::new(
+                config.series_ttl(config.tls_refresh),
+            ))),
+            timeout: Duration::from_secs(config.tls_timeout),
+            descs: descs(METRICS),
+        })
+    }
+
+    async fn update_stats(&self) -> Result<(), Error> {
+        debug!(
+            "Checking the certificates of {} TLS targets",
+            self.targets.len()
+        );
+
+        let results = join_all(
+            self.targets
+                .iter()
+                .map(|t| fetch_certificates(t.target.clone(), self.timeout)),
+        )
+        .await;
+
+        let mut certs = self.certs.lock().unwrap();
+        // the certificates of a failing target expire with the series ttl
+        for (metrics, result) in self.targets.iter().zip(results) {
+            match result {
+                Ok(fetched) => {
+                    metrics.handshake_success.set(1);
+                    let label = metrics.target.label();
+                    let cert_metrics = CertMetrics::new(&label, &fetched);
+                    certs.refresh_to(label, cert_metrics);
+                }
+                Err(e) => {
+                    warn!(
+                        "TLS handshake with {} failed: {:?}",
+                        metrics.target.label(),
+                        e
+                    );
+                    metrics.handshake_success.set(0);
+                    metrics.handshake_errors.inc();
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn fetch_certificates(target: Target, timeout: Duration) -> Result<Certificates> {
+    tokio::task::spawn_blocking(move || handshake(&target, timeout)).await?
+}
+
+// connects to the target with its host as SNI and collects its certificate chain
+fn handshake(target: &Target, timeout: Duration) -> Result<Certificates> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let verifier = Arc::new(RecordingVerifier {
+        inner: WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+            .build()?,
+        verified: Mutex::new(false),
+    });
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier.clone())
+        .with_no_client_auth();
+
+    let server_name = ServerName::try_from(target.host.clone())
+        .with_context(|| format!("invalid host name {}", target.host))?;
+    // try every address the host resolves to, e.g. both ::1 and 127.0.0.1
+    let mut socket = None;
+    let mut last_error = None;
+    for addr in (target.host.as_str(), target.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(connected) => {
+                socket = Some(connected);
+                break;
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    let mut socket = match (socket, last_error) {
+        (Some(socket), _) => socket,
+        (None, Some(e)) => return Err(e.into()),
+        (None, None) => anyhow::bail!("{} didn't resolve to any address", target.host),
+    };
+    socket.set_read_timeout(Some(timeout))?;
+    socket.set_write_timeout(Some(timeout))?;
+
+    let mut conn = ClientConnection::new(Arc::new(config), server_name)?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut socket)?;
+    }
+
+    let chain = conn
+        .peer_certificates()
+        .filter(|chain| !chain.is_empty())
+        .context("the server didn't send any certificate")?;
+    let mut certs = Certificates {
+        not_after: Vec::new(),
+        subject: String::new(),
+        issuer: String::new(),
+        sans: Vec::new(),
+        verified: *verifier.verified.lock().unwrap(),
+    };
+    for (depth, der) in chain.iter().enumerate() {
+        let (_, cert) = x509_parser::parse_x509_certificate(der)
+            .with_context(|| format!("invalid certificate at depth {}", depth))?;
+        certs.not_after.push(cert.validity().not_after.timestamp());
+
+        if depth == 0 {
+            certs.subject = cert.subject().to_string();
+            certs.issuer = cert.issuer().to_string();
+            if let Ok(Some(san)) = cert.subject_alternative_name() {
+                certs.sans = san
+                    .value
+                    .general_names
+                    .iter()
+                    .filter_map(|name| match name {
+                        GeneralName::DNSName(name) => Some(name.to_string()),
+                        GeneralName::IPAddress(ip) => ip_address(ip),
+                        _ => None,
+                    })
+                    .collect();
+            }
+        }
+    }
+    Ok(certs)
+}
+
+fn ip_address(bytes: &[u8]) -> Option<String> {
+    match bytes.len() {
+        4 => Some(std::net::Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?).to_string()),
+        16 => Some(std::net::Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?).to_string()),
+        _ => None,
+    }
+}
+
+/// Verifies the certificates like a browser would, but only records the
+/// outcome instead of failing the handshake, so that we can still export
+/// the expiry of expired or untrusted certificates.
+#[derive(Debug)]
+struct RecordingVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    verified: Mutex<bool>,
+}
+
+impl ServerCertVerifier for RecordingVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let result = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        );
+        if let Err(e) = &result {
+            debug!("Certificate of {:?} isn't valid: {}", server_name, e);
+        }
+        *self.verified.lock().unwrap() = result.is_ok();
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+impl Refresh for TlsCerts {
+    fn refresh(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(self.update_stats())
+    }
+}
+
+impl Collector for TlsCerts {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut metrics = Vec::new();
+        for target in self.targets.iter() {
+            metrics.extend(target.handshake_success.collect());
+            metrics.extend(target.handshake_errors.collect());
+        }
+
+        let now = unix_now();
+        let mut certs = self.certs.lock().unwrap();
+        certs.expire();
+        for cert in certs.values() {
+            for (gauge, not_after) in cert.days_until_expiry.iter().zip(&cert.not_after) {
+                gauge.set((not_after - now) as f64 / 86400.0);
+                metrics.extend(gauge.collect());
+            }
+            metrics.extend(cert.info.collect());
+            metrics.extend(cert.verified.collect());
+        }
+        metrics
+    }
+}
+
+pub(crate) async fn check_config(config: &Config, report: &mut Report) {
+    report.section("TLS certificates (TLS_TARGETS)");
+
+    let targets = match parse_targets(&config.tls_targets) {
+        Ok(targets) if targets.is_empty() => {
+            report.ok("TLS_TARGETS", "no targets, collector disabled");
+            return;
+        }
+        Ok(targets) => targets,
+        Err(e) => {
+            report.failure("TLS_TARGETS", &e);
+            return;
+        }
+    };
+
+    let timeout = Duration::from_secs(config.tls_timeout);
+    let now = unix_now();
+    for target in targets {
+        let label = target.label();
+        match fetch_certificates(target, timeout).await {
+            Ok(certs) => {
+                let days = (certs.not_after.iter().min().copied().unwrap_or(now) - now) / 86400;
+                let message = format!(
+                    "{} certificate(s), first expiry in {} days",
+                    certs.not_after.len(),
+                    days
+                );
+                if certs.verified {
+                    report.ok(label, message);
+                } else {
+                    report.warning(label, format!("{}, the chain isn't trusted", message));
+                }
+            }
+            // the service may just be down, which isn't a configuration error
+            Err(e) => report.warning(label, format!("TLS handshake failed: {:#}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_targets, Target};
+
+    #[test]
+    fn parse_targets_with_default_port() {
+        let target = |host: &str, port| Target {
+            host: host.to_string(),
+            port,
+        };
+        assert_eq!(
+            vec![
+                target("crates.io", 443),
+                target("static.rust-lang.org", 8443),
+                target("::1", 443),
+            ],
+            parse_targets("crates.io, static.rust-lang.org:8443,[::1]:443").unwrap()
+        );
+        assert_eq!("[::1]:443", target("::1", 443).label());
+        assert!(parse_targets("crates.io:https").is_err());
+    }
+}
@@ -1,41 +1,233 @@
+use crate::secret::{Secret, SecretSource};
 use anyhow::{Context, Error};
 use std::env::VarError;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 const ENVIRONMENT_VARIABLE_PREFIX: &str = "MONITORBOT_";
 
 #[derive(Clone, Debug)]
 pub struct Config {
     // authorization secret (token) to be able to scrape the metrics endpoint
-    pub secret: String,
+    pub secret: SecretSource,
     // http server port to bind to
     pub port: u16,
     // github api tokens to collect rate limit statistics
-    pub gh_rate_limit_tokens: String,
+    // ex. "token1,triagebot-prod=token2" where the optional alias is used as
+    // label instead of the token's github username
+    pub gh_rate_limit_tokens: SecretSource,
     // github rate limit stats data cache refresh rate frequency (in seconds)
     pub gh_rate_limit_stats_cache_refresh: u64,
     // github api token to be used when querying for gha runner's status
     // note: token must have (repo scope) authorization
-    pub github_token: String,
+    pub github_token: SecretSource,
     // gh runner's repos to track they status. multiple repos are allowed
     // ex. "rust,cargo,docs.rs"
     pub gha_runners_repos: String,
     // gha runner's status refresh rate frequency (in seconds)
     pub gha_runners_cache_refresh: u64,
+    // time runners that are no longer registered are still reported for (in seconds)
+    // note: 0 drops them as soon as they're gone
+    pub gha_runners_deregistered_retention: u64,
+    // base url of the github api, only needs to be changed for testing or github enterprise
+    pub github_api_url: String,
+    // directory to save every http response to as test fixtures, with credentials scrubbed
+    pub http_record_dir: Option<PathBuf>,
+    // directory of test fixtures to answer every http request with instead of the network
+    pub http_replay_dir: Option<PathBuf>,
+    // time after which series that are no longer reported are dropped (in seconds)
+    // note: raised to twice the refresh interval of a collector when shorter than that,
+    // 0 keeps them forever
+    pub series_ttl: u64,
+    // threshold rules evaluated after every refresh, separated by semicolons
+    // ex. "gha_runner_online == 0 for 10m; github_rate_limit_remaining / github_rate_limit_limit < 0.1"
+    // note: empty disables the built-in alerting
+    pub alert_rules: String,
+    // webhooks to notify of firing and resolved alerts, along with the style of their messages
+    // ex. "zulip=https://example.zulipchat.com/api/v1/external/slack_incoming?...,slack=https://hooks.slack.com/..."
+    pub alert_webhooks: Option<SecretSource>,
+    // directory to save the last good data of every collector to, restored on startup
+    pub state_dir: Option<PathBuf>,
+    // urls to probe along with the checks their response must pass, separated by commas
+    // ex. "https://crates.io/api/v1/summary body=num_crates, https://www.rust-lang.org/ header=content-type:text/html"
+    // note: empty disables the collector
+    pub http_probe_targets: String,
+    // http probes refresh rate frequency (in seconds)
+    pub http_probe_refresh: u64,
+    // time after which a probe is considered failed (in seconds)
+    pub http_probe_timeout: u64,
+    // host:port to check the certificates of, separated by commas, the port defaults to 443
+    // ex. "crates.io,static.rust-lang.org:443"
+    // note: empty disables the collector
+    pub tls_targets: String,
+    // tls certificates refresh rate frequency (in seconds)
+    pub tls_refresh: u64,
+    // time after which a tls handshake is considered failed (in seconds)
+    pub tls_timeout: u64,
+    // dns records to resolve along with their expected values, separated by commas
+    // ex. "crates.io A=13.32.1.1|13.32.1.2, static.rust-lang.org CNAME=d3ah34wvbudrdd.cloudfront.net, docs.rs AAAA"
+    // note: empty disables the collector
+    pub dns_records: String,
+    // address of the dns resolver to query
+    pub dns_resolver: SocketAddr,
+    // dns records refresh rate frequency (in seconds)
+    pub dns_refresh: u64,
+    // time after which a dns query is considered failed (in seconds)
+    pub dns_timeout: u64,
+    // base url the rust channel manifests are published under
+    pub rust_dist_url: String,
+    // release channels to check the manifests of, separated by commas
+    // ex. "nightly,beta,stable"
+    // note: empty disables the collector
+    pub rust_channels: String,
+    // targets every checked component must be available for, separated by commas
+    pub rust_channels_targets: String,
+    // components to check the availability of, separated by commas
+    // note: renamed components are resolved, so "rustfmt" checks "rustfmt-preview"
+    pub rust_channels_components: String,
+    // rust channel manifests refresh rate frequency (in seconds)
+    pub rust_channels_refresh: u64,
+    // base url of the crates.io api, whose recently updated crates are looked up in the indexes
+    pub crates_io_api_url: String,
+    // base url of the sparse crates.io index
+    // ex. "https://index.crates.io"
+    // note: empty disables checking the sparse index
+    pub crates_index_url: String,
+    // url of the git crates.io index remote
+    // ex. "https://github.com/rust-lang/crates.io-index"
+    // note: empty disables checking the git index
+    pub crates_index_git_url: String,
+    // crates.io index freshness refresh rate frequency (in seconds)
+    pub crates_index_refresh: u64,
+    // base url of docs.rs, whose build queue and status are polled
+    // ex. "https://docs.rs"
+    // note: empty disables the collector
+    pub docsrs_url: String,
+    // docs.rs queue refresh rate frequency (in seconds)
+    pub docsrs_refresh: u64,
+    // base url of the bors (homu) instance whose queues are tracked
+    pub bors_url: String,
+    // repos to track the bors queue of, separated by commas
+    // ex. "rust-lang/rust,rust-lang/cargo"
+    // note: empty disables the collector, the last merge is fetched with the github token
+    pub bors_repos: String,
+    // bors queue refresh rate frequency (in seconds)
+    pub bors_refresh: u64,
+    // repos to track the webhook deliveries of, separated by commas
+    // ex. "rust-lang/rust,rust-lang/cargo"
+    // note: the github token must have the admin:repo_hook scope
+    pub webhook_repos: String,
+    // orgs to track the webhook deliveries of, separated by commas
+    // ex. "rust-lang"
+    // note: the github token must have the admin:org_hook scope, the collector is
+    // disabled when neither repos nor orgs are configured
+    pub webhook_orgs: String,
+    // most recent deliveries to inspect per hook
+    pub webhook_deliveries: usize,
+    // webhook deliveries refresh rate frequency (in seconds)
+    pub webhook_refresh: u64,
+    // workflows to track the runs of on a branch, as owner/repo/workflow@branch separated by commas
+    // ex. "rust-lang/rust/ci.yml@master,rust-lang/cargo/main.yml@master"
+    // note: empty disables the collector, the runs are listed with the github token
+    pub gha_workflows: String,
+    // most recent completed runs to inspect per workflow (between 1 and 100)
+    pub gha_workflow_runs: usize,
+    // workflow runs refresh rate frequency (in seconds)
+    pub gha_workflows_refresh: u64,
 }
 
 impl Config {
     pub fn from_env() -> Result<Self, Error> {
         Ok(Self {
-            secret: require_env("SECRET")?,
+            secret: require_secret_env("SECRET")?,
             port: default_env("PORT", 3001)?,
-            gh_rate_limit_tokens: require_env("RATE_LIMIT_TOKENS")?,
+            gh_rate_limit_tokens: require_secret_env("RATE_LIMIT_TOKENS")?,
             gh_rate_limit_stats_cache_refresh: default_env("GH_RATE_LIMIT_STATS_REFRESH", 120)?,
-            github_token: require_env("GITHUB_TOKEN")?,
+            github_token: require_secret_env("GITHUB_TOKEN")?,
             gha_runners_repos: require_env("RUNNERS_REPOS")?,
             gha_runners_cache_refresh: default_env("GHA_RUNNERS_REFRESH", 120)?,
+            gha_runners_deregistered_retention: default_env(
+                "GHA_RUNNERS_DEREGISTERED_RETENTION",
+                3600,
+            )?,
+            github_api_url: default_env("GITHUB_API_URL", "https://api.github.com".to_string())?
+                .trim_end_matches('/')
+                .to_string(),
+            http_record_dir: maybe_env("HTTP_RECORD_DIR")?,
+            http_replay_dir: maybe_env("HTTP_REPLAY_DIR")?,
+            series_ttl: default_env("SERIES_TTL", 600)?,
+            alert_rules: default_env("ALERT_RULES", String::new())?,
+            alert_webhooks: maybe_secret_env("ALERT_WEBHOOKS")?,
+            state_dir: maybe_env("STATE_DIR")?,
+            http_probe_targets: default_env("HTTP_PROBE_TARGETS", String::new())?,
+            http_probe_refresh: default_env("HTTP_PROBE_REFRESH", 60)?,
+            http_probe_timeout: default_env("HTTP_PROBE_TIMEOUT", 10)?,
+            tls_targets: default_env("TLS_TARGETS", String::new())?,
+            tls_refresh: default_env("TLS_REFRESH", 3600)?,
+            tls_timeout: default_env("TLS_TIMEOUT", 10)?,
+            dns_records: default_env("DNS_RECORDS", String::new())?,
+            dns_resolver: default_env("DNS_RESOLVER", ([1, 1, 1, 1], 53).into())?,
+            dns_refresh: default_env("DNS_REFRESH", 300)?,
+            dns_timeout: default_env("DNS_TIMEOUT", 5)?,
+            rust_dist_url: default_env(
+                "RUST_DIST_URL",
+                "https://static.rust-lang.org/dist".to_string(),
+            )?
+            .trim_end_matches('/')
+            .to_string(),
+            rust_channels: default_env("RUST_CHANNELS", String::new())?,
+            rust_channels_targets: default_env(
+                "RUST_CHANNELS_TARGETS",
+                "x86_64-unknown-linux-gnu,aarch64-unknown-linux-gnu,x86_64-pc-windows-msvc,aarch64-apple-darwin"
+                    .to_string(),
+            )?,
+            rust_channels_components: default_env(
+                "RUST_CHANNELS_COMPONENTS",
+                "rustc,cargo,rust-std,rust-docs,rustfmt,clippy".to_string(),
+            )?,
+            rust_channels_refresh: default_env("RUST_CHANNELS_REFRESH", 600)?,
+            crates_io_api_url: default_env(
+                "CRATES_IO_API_URL",
+                "https://crates.io/api/v1".to_string(),
+            )?
+            .trim_end_matches('/')
+            .to_string(),
+            crates_index_url: default_env("CRATES_INDEX_URL", String::new())?
+                .trim_end_matches('/')
+                .to_string(),
+            crates_index_git_url: default_env("CRATES_INDEX_GIT_URL", String::new())?
+                .trim_end_matches('/')
+                .to_string(),
+            crates_index_refresh: default_env("CRATES_INDEX_REFRESH", 60)?,
+            docsrs_url: default_env("DOCSRS_URL", String::new())?
+                .trim_end_matches('/')
+                .to_string(),
+            docsrs_refresh: default_env("DOCSRS_REFRESH", 60)?,
+            bors_url: default_env("BORS_URL", "https://bors.rust-lang.org".to_string())?
+                .trim_end_matches('/')
+                .to_string(),
+            bors_repos: default_env("BORS_REPOS", String::new())?,
+            bors_refresh: default_env("BORS_REFRESH", 60)?,
+            webhook_repos: default_env("WEBHOOK_REPOS", String::new())?,
+            webhook_orgs: default_env("WEBHOOK_ORGS", String::new())?,
+            webhook_deliveries: default_env("WEBHOOK_DELIVERIES", 100)?,
+            webhook_refresh: default_env("WEBHOOK_REFRESH", 300)?,
+            gha_workflows: default_env("GHA_WORKFLOWS", String::new())?,
+            gha_workflow_runs: default_env("GHA_WORKFLOW_RUNS", 50)?,
+            gha_workflows_refresh: default_env("GHA_WORKFLOWS_REFRESH", 300)?,
         })
     }
+
+    /// Time after which the series of a collector refreshed every `refresh`
+    /// seconds are dropped, long enough for them to survive a refresh.
+    pub(crate) fn series_ttl(&self, refresh: u64) -> Option<Duration> {
+        match self.series_ttl {
+            0 => None,
+            secs => Some(Duration::from_secs(secs.max(2 * refresh))),
+        }
+    }
 }
 
 fn maybe_env<T>(name: &str) -> Result<Option<T>, Error>
@@ -77,14 +269,54 @@ where
     Ok(maybe_env::<T>(name)?.unwrap_or(default))
 }
 
+// secrets can be passed either as `{name}` or as a path to a file in `{name}_FILE`
+fn maybe_secret_env(name: &str) -> Result<Option<SecretSource>, Error> {
+    let file_name = format!("{}_FILE", name);
+    match (
+        maybe_env::<String>(name)?,
+        maybe_env::<PathBuf>(&file_name)?,
+    ) {
+        (Some(value), None) => Ok(Some(SecretSource::Value(Secret::new(value)))),
+        (None, Some(path)) => {
+            let secret = SecretSource::file(path);
+            // fail early if the file can't be read
+            secret
+                .get()
+                .with_context(|| format!("the {} environment variable is invalid", file_name))?;
+            Ok(Some(secret))
+        }
+        (Some(_), Some(_)) => anyhow::bail!(
+            "only one of the environment variables {prefix}{} and {prefix}{} can be set",
+            name,
+            file_name,
+            prefix = ENVIRONMENT_VARIABLE_PREFIX
+        ),
+        (None, None) => Ok(None),
+    }
+}
+
+fn require_secret_env(name: &str) -> Result<SecretSource, Error> {
+    match maybe_secret_env(name)? {
+        Some(secret) => Ok(secret),
+        None => anyhow::bail!(
+            "missing environment variable {prefix}{} or {prefix}{}_FILE",
+            name,
+            name,
+            prefix = ENVIRONMENT_VARIABLE_PREFIX
+        ),
+    }
+}
+
 #[cfg(test)]
+// the tests predate these lints, ci only lints the library itself
+#[allow(clippy::unnecessary_to_owned)]
 mod tests {
     // note: if you add new unit tests here and need to set up an env var
     // you need to use a unique env var name for your test. cargo by default will run
     // your tests in parallel using threads and one test setup may interfere with
     // another test's outcome if they both share the same env var name.
     use super::ENVIRONMENT_VARIABLE_PREFIX;
-    use super::{default_env, maybe_env, require_env};
+    use super::{default_env, maybe_env, maybe_secret_env, require_env, require_secret_env};
 
     #[test]
     fn config_some_value_not_present() {
@@ -181,4 +413,82 @@ mod tests {
 
         panic!("expected an Err");
     }
+
+    #[test]
+    fn config_secret_from_file_is_reloaded() {
+        let path =
+            std::env::temp_dir().join(format!("monitorbot-test-secret-{}", std::process::id()));
+        std::fs::write(&path, "first-secret\n").unwrap();
+        std::env::set_var(
+            format!("{}TEST_SECRET_FILE", ENVIRONMENT_VARIABLE_PREFIX),
+            &path,
+        );
+
+        let secret = require_secret_env("TEST_SECRET").unwrap();
+        assert_eq!("first-secret", secret.get().unwrap().expose_secret());
+
+        std::fs::write(&path, "rotated-secret-value\n").unwrap();
+        assert_eq!(
+            "rotated-secret-value",
+            secret.get().unwrap().expose_secret()
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn config_secret_from_value() {
+        std::env::set_var(
+            format!("{}TEST_SECRET_VALUE", ENVIRONMENT_VARIABLE_PREFIX),
+            "plain-secret",
+        );
+
+        let secret = require_secret_env("TEST_SECRET_VALUE").unwrap();
+        assert_eq!("plain-secret", secret.get().unwrap().expose_secret());
+        assert!(!format!("{:?}", secret).contains("plain-secret"));
+    }
+
+    #[test]
+    fn config_secret_value_and_file_conflict() {
+        std::env::set_var(
+            format!("{}TEST_SECRET_BOTH", ENVIRONMENT_VARIABLE_PREFIX),
+            "plain-secret",
+        );
+        std::env::set_var(
+            format!("{}TEST_SECRET_BOTH_FILE", ENVIRONMENT_VARIABLE_PREFIX),
+            "/nonexistent/secret",
+        );
+
+        let err = maybe_secret_env("TEST_SECRET_BOTH").unwrap_err();
+        assert_eq!(
+            format!(
+                "only one of the environment variables {prefix}TEST_SECRET_BOTH and {prefix}TEST_SECRET_BOTH_FILE can be set",
+                prefix = ENVIRONMENT_VARIABLE_PREFIX
+            ),
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn config_secret_file_missing() {
+        std::env::set_var(
+            format!("{}TEST_SECRET_MISSING_FILE", ENVIRONMENT_VARIABLE_PREFIX),
+            "/nonexistent/secret",
+        );
+
+        // the file is read on startup rather than on the first use of the secret
+        assert!(maybe_secret_env("TEST_SECRET_MISSING").is_err());
+    }
+
+    #[test]
+    fn config_secret_not_present() {
+        let err = require_secret_env("SECRET_NOT_PRESENT").unwrap_err();
+        assert_eq!(
+            format!(
+                "missing environment variable {prefix}SECRET_NOT_PRESENT or {prefix}SECRET_NOT_PRESENT_FILE",
+                prefix = ENVIRONMENT_VARIABLE_PREFIX
+            ),
+            err.to_string()
+        );
+    }
 }
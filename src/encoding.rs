@@ -0,0 +1,291 @@
+use anyhow::{Error, Result};
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::{Encoder, TextEncoder};
+use serde_json::{json, Map, Value};
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Output formats of gathered metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The Prometheus text exposition format, as served on `/metrics`
+    Text,
+    /// The OpenMetrics text format
+    OpenMetrics,
+    /// JSON, for consumption by scripts
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "openmetrics" => Ok(Self::OpenMetrics),
+            "json" => Ok(Self::Json),
+            _ => anyhow::bail!("unknown format {}, expected text, openmetrics or json", s),
+        }
+    }
+}
+
+pub fn encode(families: &[MetricFamily], format: Format) -> Result<Vec<u8>> {
+    match format {
+        Format::Text => {
+            let mut buffer = Vec::new();
+            TextEncoder::new().encode(families, &mut buffer)?;
+            Ok(buffer)
+        }
+        Format::OpenMetrics => Ok(encode_openmetrics(families).into_bytes()),
+        Format::Json => Ok(serde_json::to_vec_pretty(&encode_json(families))?),
+    }
+}
+
+fn encode_openmetrics(families: &[MetricFamily]) -> String {
+    let mut out = String::new();
+    for family in families {
+        let sample_name = family.get_name();
+        // counters are named without the `_total` suffix in OpenMetrics, which
+        // is only added to their samples
+        let name = match family.get_field_type() {
+            MetricType::COUNTER => sample_name.trim_end_matches("_total"),
+            _ => sample_name,
+        };
+        let kind = match family.get_field_type() {
+            MetricType::COUNTER => "counter",
+            MetricType::GAUGE => "gauge",
+            MetricType::HISTOGRAM => "histogram",
+            MetricType::SUMMARY => "summary",
+            MetricType::UNTYPED => "unknown",
+        };
+
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+        writeln!(out, "# HELP {} {}", name, escape(family.get_help(), false)).unwrap();
+        for metric in family.get_metric() {
+            let labels = metric.get_label();
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    let value = metric.get_counter().get_value();
+                    sample(&mut out, &format!("{}_total", name), labels, None, value);
+                }
+                MetricType::GAUGE => {
+                    sample(&mut out, name, labels, None, metric.get_gauge().get_value())
+                }
+                MetricType::UNTYPED => sample(
+                    &mut out,
+                    name,
+                    labels,
+                    None,
+                    metric.get_untyped().get_value(),
+                ),
+                MetricType::HISTOGRAM => {
+                    let h = metric.get_histogram();
+                    let bucket = format!("{}_bucket", name);
+                    for b in h.get_bucket() {
+                        let le = format_value(b.get_upper_bound());
+                        let count = b.get_cumulative_count() as f64;
+                        sample(&mut out, &bucket, labels, Some(("le", &le)), count);
+                    }
+                    let count = h.get_sample_count() as f64;
+                    sample(&mut out, &bucket, labels, Some(("le", "+Inf")), count);
+                    sample(&mut out, &format!("{}_count", name), labels, None, count);
+                    let sum = h.get_sample_sum();
+                    sample(&mut out, &format!("{}_sum", name), labels, None, sum);
+                }
+                MetricType::SUMMARY => {
+                    let s = metric.get_summary();
+                    for q in s.get_quantile() {
+                        let quantile = format_value(q.get_quantile());
+                        let value = q.get_value();
+                        sample(&mut out, name, labels, Some(("quantile", &quantile)), value);
+                    }
+                    let count = s.get_sample_count() as f64;
+                    sample(&mut out, &format!("{}_count", name), labels, None, count);
+                    let sum = s.get_sample_sum();
+                    sample(&mut out, &format!("{}_sum", name), labels, None, sum);
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+fn sample(
+    out: &mut String,
+    name: &str,
+    labels: &[LabelPair],
+    extra: Option<(&str, &str)>,
+    value: f64,
+) {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|l| format!("{}=\"{}\"", l.get_name(), escape(l.get_value(), true)))
+        .collect();
+    if let Some((name, value)) = extra {
+        pairs.push(format!("{}=\"{}\"", name, value));
+    }
+
+    if pairs.is_empty() {
+        writeln!(out, "{} {}", name, format_value(value)).unwrap();
+    } else {
+        writeln!(
+            out,
+            "{}{{{}}} {}",
+            name,
+            pairs.join(","),
+            format_value(value)
+        )
+        .unwrap();
+    }
+}
+
+fn escape(value: &str, quotes: bool) -> String {
+    let value = value.replace('\\', "\\\\").replace('\n', "\\n");
+    if quotes {
+        value.replace('"', "\\\"")
+    } else {
+        value
+    }
+}
+
+fn format_value(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else if value.is_nan() {
+        "NaN".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn encode_json(families: &[MetricFamily]) -> Value {
+    let families = families
+        .iter()
+        .map(|family| {
+            let kind = match family.get_field_type() {
+                MetricType::COUNTER => "counter",
+                MetricType::GAUGE => "gauge",
+                MetricType::HISTOGRAM => "histogram",
+                MetricType::SUMMARY => "summary",
+                MetricType::UNTYPED => "untyped",
+            };
+            let metrics: Vec<Value> = family
+                .get_metric()
+                .iter()
+                .map(|metric| json_metric(family.get_field_type(), metric))
+                .collect();
+            json!({
+                "name": family.get_name(),
+                "help": family.get_help(),
+                "type": kind,
+                "metrics": metrics,
+            })
+        })
+        .collect();
+    Value::Array(families)
+}
+
+fn json_metric(kind: MetricType, metric: &Metric) -> Value {
+    let labels: Map<String, Value> = metric
+        .get_label()
+        .iter()
+        .map(|l| (l.get_name().to_string(), Value::from(l.get_value())))
+        .collect();
+
+    match kind {
+        MetricType::COUNTER => {
+            json!({ "labels": labels, "value": metric.get_counter().get_value() })
+        }
+        MetricType::GAUGE => json!({ "labels": labels, "value": metric.get_gauge().get_value() }),
+        MetricType::UNTYPED => {
+            json!({ "labels": labels, "value": metric.get_untyped().get_value() })
+        }
+        MetricType::HISTOGRAM => {
+            let h = metric.get_histogram();
+            let buckets: Map<String, Value> = h
+                .get_bucket()
+                .iter()
+                .map(|b| {
+                    let count = Value::from(b.get_cumulative_count());
+                    (format_value(b.get_upper_bound()), count)
+                })
+                .collect();
+            json!({
+                "labels": labels,
+                "buckets": buckets,
+                "count": h.get_sample_count(),
+                "sum": h.get_sample_sum(),
+            })
+        }
+        MetricType::SUMMARY => {
+            let s = metric.get_summary();
+            let quantiles: Map<String, Value> = s
+                .get_quantile()
+                .iter()
+                .map(|q| (format_value(q.get_quantile()), Value::from(q.get_value())))
+                .collect();
+            json!({
+                "labels": labels,
+                "quantiles": quantiles,
+                "count": s.get_sample_count(),
+                "sum": s.get_sample_sum(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, Format};
+    use prometheus::{IntCounter, IntGauge, Opts, Registry};
+
+    fn registry() -> Registry {
+        let registry = Registry::new_custom(Some("monitorbot".to_string()), None).unwrap();
+        let gauge = IntGauge::with_opts(
+            Opts::new("online", "runner is online")
+                .namespace("gha_runner")
+                .const_label("runner", "aws-1"),
+        )
+        .unwrap();
+        gauge.set(1);
+        let counter =
+            IntCounter::with_opts(Opts::new("exhausted_total", "rate limit ran out")).unwrap();
+        counter.inc();
+        registry.register(Box::new(gauge)).unwrap();
+        registry.register(Box::new(counter)).unwrap();
+        registry
+    }
+
+    #[test]
+    fn openmetrics_output() {
+        let output = encode(&registry().gather(), Format::OpenMetrics).unwrap();
+        assert_eq!(
+            "# TYPE monitorbot_exhausted counter\n\
+             # HELP monitorbot_exhausted rate limit ran out\n\
+             monitorbot_exhausted_total 1\n\
+             # TYPE monitorbot_gha_runner_online gauge\n\
+             # HELP monitorbot_gha_runner_online runner is online\n\
+             monitorbot_gha_runner_online{runner=\"aws-1\"} 1\n\
+             # EOF\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn json_output() {
+        let output = encode(&registry().gather(), Format::Json).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "name": "monitorbot_gha_runner_online",
+                "help": "runner is online",
+                "type": "gauge",
+                "metrics": [{ "labels": { "runner": "aws-1" }, "value": 1.0 }],
+            }),
+            value[1]
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use crate::collectors::COLLECTORS;
+use crate::METRICS_PREFIX;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::fmt::Write;
+use std::path::Path;
+
+/// File name of the generated Prometheus alerting rules.
+pub const RULES_FILE: &str = "monitorbot.rules.yml";
+/// File name of the generated Grafana dashboard.
+pub const DASHBOARD_FILE: &str = "monitorbot.dashboard.json";
+
+// width of a dashboard panel, two panels fit in a row
+const PANEL_WIDTH: u64 = 12;
+const PANEL_HEIGHT: u64 = 8;
+
+/// Writes the alerting rules and the dashboard declared by the collectors
+/// into `dir`.
+pub fn generate(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("unable to create directory {}", dir.display()))?;
+    for (file, content) in [
+        (RULES_FILE, prometheus_rules()?),
+        (DASHBOARD_FILE, grafana_dashboard()?),
+    ] {
+        let path = dir.join(file);
+        std::fs::write(&path, content)
+            .with_context(|| format!("unable to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Prometheus rules file with one group of alerting rules per collector.
+pub fn prometheus_rules() -> Result<String> {
+    let mut out = String::from("# generated by `monitorbot generate`, do not edit\ngroups:\n");
+    for collector in COLLECTORS.iter().filter(|c| !c.alerts.is_empty()) {
+        writeln!(out, "  - name: {}_{}", METRICS_PREFIX, collector.name)?;
+        writeln!(out, "    rules:")?;
+        for alert in collector.alerts {
+            // JSON strings are valid double quoted YAML scalars
+            writeln!(out, "      - alert: {}", alert.name)?;
+            writeln!(out, "        expr: {}", json!(expand(alert.expr)?))?;
+            writeln!(out, "        for: {}", alert.for_)?;
+            writeln!(out, "        labels:")?;
+            writeln!(out, "          severity: {}", alert.severity)?;
+            writeln!(out, "        annotations:")?;
+            writeln!(out, "          summary: {}", json!(alert.summary))?;
+        }
+    }
+    Ok(out)
+}
+
+/// Grafana dashboard with one row of panels per collector.
+pub fn grafana_dashboard() -> Result<String> {
+    let datasource = json!({ "type": "prometheus", "uid": "${datasource}" });
+    let mut panels = Vec::new();
+    let mut y = 0;
+    for collector in COLLECTORS.iter().filter(|c| !c.panels.is_empty()) {
+        panels.push(json!({
+            "id": panels.len() + 1,
+            "type": "row",
+            "title": collector.name,
+            "collapsed": false,
+            "gridPos": { "h": 1, "w": 2 * PANEL_WIDTH, "x": 0, "y": y },
+            "panels": [],
+        }));
+        y += 1;
+
+        for (i, panel) in collector.panels.iter().enumerate() {
+            let x = (i as u64 % 2) * PANEL_WIDTH;
+            panels.push(json!({
+                "id": panels.len() + 1,
+                "type": "timeseries",
+                "title": panel.title,
+                "datasource": datasource,
+                "gridPos": { "h": PANEL_HEIGHT, "w": PANEL_WIDTH, "x": x, "y": y },
+                "fieldConfig": { "defaults": { "unit": panel.unit }, "overrides": [] },
+                "targets": [{
+                    "refId": "A",
+                    "datasource": datasource,
+                    "expr": expand(panel.expr)?,
+                    "legendFormat": panel.legend,
+                }],
+            }));
+            if x > 0 || i + 1 == collector.panels.len() {
+                y += PANEL_HEIGHT;
+            }
+        }
+    }
+
+    let dashboard: Value = json!({
+        "title": METRICS_PREFIX,
+        "uid": METRICS_PREFIX,
+        "description": "Generated by `monitorbot generate`, do not edit",
+        "editable": false,
+        "schemaVersion": 39,
+        "time": { "from": "now-24h", "to": "now" },
+        "refresh": "1m",
+        "templating": {
+            "list": [{
+                "name": "datasource",
+                "label": "Data source",
+                "type": "datasource",
+                "query": "prometheus",
+            }],
+        },
+        "panels": panels,
+    });
+    Ok(serde_json::to_string_pretty(&dashboard)? + "\n")
+}
+
+// replaces the `{name}` metric references of an expression with the full
+// name of the metrics, anything else between braces (e.g. label matchers) is
+// kept as is
+fn expand(expr: &str) -> Result<String> {
+    let mut out = String::new();
+    let mut rest = expr;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .with_context(|| format!("unbalanced braces in {}", expr))?;
+        let name = &rest[start + 1..end];
+        let is_reference = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if is_reference {
+            anyhow::ensure!(
+                COLLECTORS
+                    .iter()
+                    .any(|c| c.metrics.iter().any(|m| m.name == name)),
+                "unknown metric {} in {}",
+                name,
+                expr
+            );
+            write!(out, "{}_{}", METRICS_PREFIX, name)?;
+        } else {
+            out.push_str(&rest[start..=end]);
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::expand;
+
+    #[test]
+    fn expand_metric_references() {
+        assert_eq!(
+            expand("sum by (repo) ({gha_runner_online}{repo=\"rust-lang/rust\"})").unwrap(),
+            "sum by (repo) (monitorbot_gha_runner_online{repo=\"rust-lang/rust\"})"
+        );
+        assert!(expand("{gha_runner_missing} == 0").is_err());
+    }
+}
@@ -0,0 +1,405 @@
+use crate::{Config, Secret};
+use anyhow::{Context, Error, Result};
+use hyper::http;
+use reqwest::header::{HeaderMap, AUTHORIZATION};
+use reqwest::{Client, ClientBuilder, Request, RequestBuilder, Response};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const USER_AGENT: &str = "https://github.com/rust-lang/monitorbot (infra@rust-lang.org)";
+
+// response headers worth keeping in fixtures, everything else is dropped
+const RECORDED_HEADERS: &[&str] = &[
+    "content-type",
+    "link",
+    "x-oauth-scopes",
+    "x-ratelimit-limit",
+    "x-ratelimit-remaining",
+    "x-ratelimit-reset",
+    "x-ratelimit-used",
+];
+
+// query parameters whose values are replaced in fixtures, as they're commonly
+// used to pass credentials
+const SECRET_QUERY_PARAMS: &[&str] = &[
+    "access_token",
+    "api_key",
+    "apikey",
+    "client_secret",
+    "key",
+    "password",
+    "secret",
+    "sig",
+    "signature",
+    "token",
+];
+
+/// HTTP client shared by every collector.
+///
+/// Besides talking to the network it can record every response it receives
+/// as fixtures (with tokens and usernames scrubbed), or replay previously
+/// recorded fixtures without touching the network at all.
+#[derive(Clone)]
+pub struct HttpClient {
+    client: Client,
+    mode: Mode,
+}
+
+#[derive(Clone)]
+enum Mode {
+    Live,
+    Record(Arc<Recorder>),
+    Replay(Arc<Replayer>),
+}
+
+impl HttpClient {
+    /// Client using the recording or replay mode requested by the configuration.
+    pub fn from_config(config: &Config) -> Result<Self> {
+        match (&config.http_record_dir, &config.http_replay_dir) {
+            (None, None) => Self::new(),
+            (Some(dir), None) => Self::recording(dir),
+            (None, Some(dir)) => Self::replaying(dir),
+            (Some(_), Some(_)) => anyhow::bail!("can't both record and replay http fixtures"),
+        }
+    }
+
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            client: ClientBuilder::new().user_agent(USER_AGENT).build()?,
+            mode: Mode::Live,
+        })
+    }
+
+    /// Client saving every response it receives as fixtures in `dir`.
+    pub fn recording(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("unable to create fixtures directory {}", dir.display()))?;
+        Ok(Self {
+            mode: Mode::Record(Arc::new(Recorder {
+                dir,
+                tokens: Mutex::new(Vec::new()),
+                logins: Mutex::new(Vec::new()),
+            })),
+            ..Self::new()?
+        })
+    }
+
+    /// Client answering requests with the fixtures found in `dir`.
+    ///
+    /// Requests are matched on their method, host, path and query (with the
+    /// values of secret query parameters scrubbed) as well as their token,
+    /// which must be named like the scrubbed tokens of the fixtures
+    /// (`token-1`, `token-2`, ...).
+    pub fn replaying(dir: impl Into<PathBuf>) -> Result<Self> {
+        Ok(Self {
+            mode: Mode::Replay(Arc::new(Replayer::load(&dir.into())?)),
+            ..Self::new()?
+        })
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn post(&self, url: &str) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        self.execute(request.build()?).await
+    }
+
+    pub async fn execute(&self, request: Request) -> Result<Response> {
+        match &self.mode {
+            Mode::Live => Ok(self.client.execute(request).await?),
+            Mode::Record(recorder) => {
+                let key = FixtureKey::new(&request, |token| recorder.token_name(token));
+                let response = self.client.execute(request).await?;
+                recorder.record(key, response).await
+            }
+            Mode::Replay(replayer) => {
+                let key = FixtureKey::new(&request, |token| token.expose_secret().to_string());
+                replayer.replay(&key)
+            }
+        }
+    }
+}
+
+/// A recorded request along with the responses it got, in order.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Fixture {
+    #[serde(flatten)]
+    key: FixtureKey,
+    responses: Vec<RecordedResponse>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct FixtureKey {
+    method: String,
+    // host of the request, along with its port if it isn't the default one
+    host: String,
+    // path and query of the request, see `scrub_query`
+    path: String,
+    // scrubbed name of the token used for the request, if any
+    token: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct RecordedResponse {
+    status: u16,
+    headers: BTreeMap<String, String>,
+    // kept as JSON when possible to make fixtures readable and editable
+    body: Value,
+}
+
+impl FixtureKey {
+    fn new(request: &Request, token_name: impl FnOnce(&Secret) -> String) -> Self {
+        let url = request.url();
+        let host = url.host_str().unwrap_or_default();
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), scrub_query(query)),
+            None => url.path().to_string(),
+        };
+        Self {
+            method: request.method().to_string(),
+            host: match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_string(),
+            },
+            path,
+            token: request_token(request.headers()).map(|token| token_name(&token)),
+        }
+    }
+
+    // the path of the request without its query
+    fn path_without_query(&self) -> &str {
+        self.path.split('?').next().unwrap_or_default()
+    }
+
+    fn file_name(&self) -> String {
+        let mut name = format!("{} {} {}", self.method, self.host, self.path);
+        if let Some(token) = &self.token {
+            name.push(' ');
+            name.push_str(token);
+        }
+        // keep file names portable, e.g. `GET api.github.com /user token-1` is
+        // saved as `GET_api_github_com_user_token-1.json`
+        let mut file_name = String::new();
+        for c in name.chars() {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                file_name.push(c);
+            } else if !file_name.ends_with('_') {
+                file_name.push('_');
+            }
+        }
+        format!("{}.json", file_name.trim_matches('_'))
+    }
+}
+
+// replaces the values of the secret query parameters, e.g. `?api_key=abc&stream=infra`
+// becomes `?api_key=scrubbed&stream=infra`
+fn scrub_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|param| match param.split_once('=') {
+            Some((name, _))
+                if SECRET_QUERY_PARAMS.contains(&name.to_ascii_lowercase().as_str()) =>
+            {
+                format!("{}=scrubbed", name)
+            }
+            _ => param.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn request_token(headers: &HeaderMap) -> Option<Secret> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let token = value
+        .strip_prefix("token ")
+        .or_else(|| value.strip_prefix("Bearer "))?;
+    Some(Secret::new(token.to_string()))
+}
+
+struct Recorder {
+    dir: PathBuf,
+    // tokens seen so far, scrubbed as `token-{index + 1}`
+    tokens: Mutex<Vec<Secret>>,
+    // usernames seen so far, scrubbed as `user-{index + 1}`
+    logins: Mutex<Vec<String>>,
+}
+
+impl Recorder {
+    fn token_name(&self, token: &Secret) -> String {
+        let mut tokens = self.tokens.lock().unwrap();
+        let index = match tokens.iter().position(|t| t == token) {
+            Some(index) => index,
+            None => {
+                tokens.push(token.clone());
+                tokens.len() - 1
+            }
+        };
+        format!("token-{}", index + 1)
+    }
+
+    async fn record(&self, key: FixtureKey, response: Response) -> Result<Response> {
+        let status = response.status();
+        let version = response.version();
+        let headers = response.headers().clone();
+        let body = response.bytes().await?;
+
+        let recorded = RecordedResponse {
+            status: status.as_u16(),
+            headers: headers
+                .iter()
+                .filter(|(name, _)| RECORDED_HEADERS.contains(&name.as_str()))
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), self.scrub(value.to_str().ok()?)))
+                })
+                .collect(),
+            body: self.scrub_body(&key, &body),
+        };
+
+        let path = self.dir.join(key.file_name());
+        let mut fixture = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .with_context(|| format!("invalid fixture {}", path.display()))?,
+            Err(_) => Fixture {
+                key,
+                responses: Vec::new(),
+            },
+        };
+        fixture.responses.push(recorded);
+        std::fs::write(&path, serde_json::to_vec_pretty(&fixture)?)
+            .with_context(|| format!("unable to write fixture {}", path.display()))?;
+
+        let mut builder = http::Response::builder().status(status).version(version);
+        for (name, value) in headers.iter() {
+            builder = builder.header(name, value);
+        }
+        Ok(Response::from(builder.body(body.to_vec())?))
+    }
+
+    fn scrub_body(&self, key: &FixtureKey, body: &[u8]) -> Value {
+        let mut value: Value = match serde_json::from_slice(body) {
+            Ok(value) => value,
+            Err(_) => return Value::String(self.scrub(&String::from_utf8_lossy(body))),
+        };
+
+        // only the login of the authenticated user is used, drop everything
+        // else it tells about the user (name, email, plan, ...). The api can
+        // be under a path prefix, e.g. `/api/v3/user` for GitHub Enterprise
+        if key.path_without_query().ends_with("/user") {
+            if let Some(login) = value.get("login").and_then(Value::as_str) {
+                let mut logins = self.logins.lock().unwrap();
+                if !logins.iter().any(|l| l == login) {
+                    logins.push(login.to_string());
+                }
+            }
+            value = serde_json::json!({ "login": value.get("login") });
+        }
+
+        serde_json::from_str(&self.scrub(&value.to_string())).unwrap_or(value)
+    }
+
+    fn scrub(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for (i, token) in self.tokens.lock().unwrap().iter().enumerate() {
+            text = text.replace(token.expose_secret(), &format!("token-{}", i + 1));
+        }
+        for (i, login) in self.logins.lock().unwrap().iter().enumerate() {
+            text = text.replace(login.as_str(), &format!("user-{}", i + 1));
+        }
+        text
+    }
+}
+
+struct Replayer {
+    fixtures: Mutex<Vec<Fixture>>,
+}
+
+impl Replayer {
+    fn load(dir: &Path) -> Result<Self> {
+        let mut fixtures = Vec::new();
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("unable to read fixtures directory {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension() == Some("json".as_ref()) {
+                let content = std::fs::read(&path)?;
+                let fixture: Fixture = serde_json::from_slice(&content)
+                    .with_context(|| format!("invalid fixture {}", path.display()))?;
+                fixtures.push(fixture);
+            }
+        }
+        Ok(Self {
+            fixtures: Mutex::new(fixtures),
+        })
+    }
+
+    // responses are replayed in the order they were recorded, the last one
+    // is then repeated for every following request
+    fn replay(&self, key: &FixtureKey) -> Result<Response> {
+        let mut fixtures = self.fixtures.lock().unwrap();
+        let fixture = fixtures
+            .iter_mut()
+            .find(|fixture| fixture.key == *key)
+            .with_context(|| {
+                format!(
+                    "no recorded response for {} {}{} (token: {})",
+                    key.method,
+                    key.host,
+                    key.path,
+                    key.token.as_deref().unwrap_or("none")
+                )
+            })?;
+        let recorded = match fixture.responses.len() {
+            0 => anyhow::bail!("fixture for {} {} has no responses", key.method, key.path),
+            1 => fixture.responses[0].clone(),
+            _ => fixture.responses.remove(0),
+        };
+
+        let body = match recorded.body {
+            Value::String(body) => body.into_bytes(),
+            body => serde_json::to_vec(&body)?,
+        };
+        let mut builder = http::Response::builder().status(recorded.status);
+        for (name, value) in recorded.headers.iter() {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        Ok(Response::from(builder.body(body).map_err(Error::from)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{scrub_query, FixtureKey};
+
+    #[test]
+    fn secret_query_params_are_scrubbed() {
+        assert_eq!(
+            "api_key=scrubbed&stream=infra&Token=scrubbed",
+            scrub_query("api_key=zulip-secret&stream=infra&Token=ghp_x")
+        );
+        assert_eq!("per_page=100&page=2", scrub_query("per_page=100&page=2"));
+    }
+
+    #[test]
+    fn fixtures_are_keyed_by_host() {
+        let key = |url: &str| {
+            let request = reqwest::Client::new().get(url).build().unwrap();
+            FixtureKey::new(&request, |_| unreachable!())
+        };
+        let crates_io = key("https://crates.io/api/v1/summary");
+        let local = key("http://127.0.0.1:8080/api/v1/summary?api_key=abc");
+        assert_ne!(crates_io, local);
+        assert_eq!("GET_crates_io_api_v1_summary.json", crates_io.file_name());
+        assert_eq!(
+            "GET_127_0_0_1_8080_api_v1_summary_api_key_scrubbed.json",
+            local.file_name()
+        );
+    }
+}
@@ -1,12 +1,22 @@
 #![allow(clippy::new_without_default)]
 
+mod alerts;
+pub mod catalog;
+mod check;
 pub mod collectors;
 mod config;
+pub mod encoding;
+pub mod generate;
+mod http;
+mod secret;
 
+pub use check::{check_config, Report};
 pub use config::Config;
+pub use http::HttpClient;
+pub use secret::{Secret, SecretSource};
 
 use prometheus::core::Collector;
-use prometheus::{Encoder, Registry};
+use prometheus::Registry;
 
 use anyhow::{Error, Result};
 use futures::future;
@@ -17,6 +27,9 @@ use hyper::service::Service;
 use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
 use log::{debug, error};
 
+// prefix of every metric exported by monitorbot
+const METRICS_PREFIX: &str = "monitorbot";
+
 #[derive(Clone, Debug)]
 pub struct MetricProvider {
     register: prometheus::Registry,
@@ -25,7 +38,7 @@ pub struct MetricProvider {
 
 impl MetricProvider {
     pub fn new(config: Config) -> Self {
-        let register = Registry::new_custom(Some("monitorbot".to_string()), None)
+        let register = Registry::new_custom(Some(METRICS_PREFIX.to_string()), None)
             .expect("Unable to build Registry");
         Self { register, config }
     }
@@ -36,13 +49,9 @@ impl MetricProvider {
             .map_err(Error::from)
     }
 
-    fn gather_with_encoder<BUF>(&self, encoder: impl Encoder, buf: &mut BUF) -> Result<(), Error>
-    where
-        BUF: std::io::Write,
-    {
-        encoder
-            .encode(&self.register.gather(), buf)
-            .map_err(Error::from)
+    /// Gathers the metrics of every registered collector in the given format.
+    pub fn gather(&self, format: encoding::Format) -> Result<Vec<u8>, Error> {
+        encoding::encode(&self.register.gather(), format)
     }
 
     pub fn into_service(self) -> MetricProviderFactory {
@@ -62,16 +71,38 @@ impl Service<Request<Body>> for MetricProvider {
     fn call(&mut self, req: Request<Body>) -> Self::Future {
         debug!("New Request to endpoint {}", req.uri().path());
 
-        let authorized = is_auth_token_valid(&self.config.secret, req.headers());
+        let authorized = match self.config.secret.get() {
+            Ok(secret) => is_auth_token_valid(secret.expose_secret(), req.headers()),
+            Err(e) => {
+                error!("Unable to read the metrics endpoint secret: {:?}", e);
+                false
+            }
+        };
         let output = match (req.method(), req.uri().path(), authorized) {
             // Metrics handler
-            (&Method::GET, "/metrics", true) => {
-                let encoder = prometheus::TextEncoder::new();
-                let mut buffer = Vec::<u8>::new();
-                match self.gather_with_encoder(encoder, &mut buffer) {
-                    Ok(_) => Response::builder()
+            (&Method::GET, "/metrics", true) => match self.gather(encoding::Format::Text) {
+                Ok(buffer) => Response::builder()
+                    .status(StatusCode::OK)
+                    .body(Body::from(buffer))
+                    .unwrap(),
+                Err(e) => {
+                    error!("{:?}", e);
+                    Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Body::empty())
+                        .unwrap()
+                }
+            },
+            // Metric catalog handler, JSON by default or `?format=markdown`
+            (&Method::GET, "/catalog", true) => {
+                let format = match req.uri().query() {
+                    Some("format=markdown") => catalog::CatalogFormat::Markdown,
+                    _ => catalog::CatalogFormat::Json,
+                };
+                match catalog::render(format) {
+                    Ok(catalog) => Response::builder()
                         .status(StatusCode::OK)
-                        .body(Body::from(buffer))
+                        .body(Body::from(catalog))
                         .unwrap(),
                     Err(e) => {
                         error!("{:?}", e);
@@ -83,10 +114,12 @@ impl Service<Request<Body>> for MetricProvider {
                 }
             }
             // Unauthorized request
-            (&Method::GET, "/metrics", false) => Response::builder()
-                .status(StatusCode::UNAUTHORIZED)
-                .body(Body::empty())
-                .unwrap(),
+            (&Method::GET, "/metrics", false) | (&Method::GET, "/catalog", false) => {
+                Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .body(Body::empty())
+                    .unwrap()
+            }
             // All other paths and methods
             _ => Response::builder()
                 .status(StatusCode::OK)
@@ -131,6 +164,8 @@ impl<T> Service<T> for MetricProviderFactory {
 }
 
 #[cfg(test)]
+// the tests predate this lint, ci only lints the library itself
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use crate::is_auth_token_valid;
     use hyper::http::HeaderValue;
@@ -1,23 +1,86 @@
 use anyhow::{Context, Error};
+use clap::{Parser, Subcommand};
 use hyper::Server;
 use log::info;
+use monitorbot::catalog::CatalogFormat;
+use monitorbot::encoding::Format;
 use monitorbot::Config;
 use monitorbot::{collectors::register_collectors, MetricProvider};
+use std::io::Write;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(about = "Monitors the APIs and services hosted by the Rust infrastructure team")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve the metrics endpoint (default)
+    Serve,
+    /// Validate the configuration and verify every token and repository
+    CheckConfig,
+    /// Refresh the collectors once and print the gathered metrics
+    Collect {
+        /// Only run this collector, can be repeated (default: every collector)
+        #[arg(long = "collector", value_name = "NAME")]
+        collectors: Vec<String>,
+        /// Output format: text, openmetrics or json
+        #[arg(long, default_value = "text")]
+        format: Format,
+    },
+    /// Print every metric exported by monitorbot
+    Catalog {
+        /// Output format: json or markdown
+        #[arg(long, default_value = "markdown")]
+        format: CatalogFormat,
+    },
+    /// Write the recommended Prometheus alerting rules and Grafana dashboard
+    Generate {
+        /// Directory to write monitorbot.rules.yml and monitorbot.dashboard.json to
+        #[arg(long, value_name = "DIR", default_value = ".")]
+        output_dir: PathBuf,
+    },
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     dotenv::dotenv().ok();
     env_logger::init();
 
+    let cli = Cli::parse();
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve().await,
+        Command::CheckConfig => {
+            let report = monitorbot::check_config().await;
+            print!("{}", report);
+            if report.has_errors() {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Collect { collectors, format } => collect(&collectors, format).await,
+        Command::Catalog { format } => {
+            println!("{}", monitorbot::catalog::render(format)?);
+            Ok(())
+        }
+        Command::Generate { output_dir } => monitorbot::generate::generate(&output_dir),
+    }
+}
+
+async fn serve() -> Result<(), Error> {
     let config = Config::from_env()?;
     let port = config.port;
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
 
     let provider = MetricProvider::new(config);
-    register_collectors(&provider)
+    register_collectors(&provider, &[])
         .await
-        .context("Failed to register collectors")?;
+        .context("Failed to register collectors")?
+        .spawn();
 
     let server = Server::bind(&addr).serve(provider.into_service());
     info!("Server listening on port: {}", port);
@@ -26,3 +89,23 @@ async fn main() -> Result<(), Error> {
 
     Ok(())
 }
+
+async fn collect(only: &[String], format: Format) -> Result<(), Error> {
+    let config = Config::from_env()?;
+    let provider = MetricProvider::new(config);
+    let collectors = register_collectors(&provider, only)
+        .await
+        .context("Failed to register collectors")?;
+
+    let failed = collectors.refresh_once().await;
+    for (name, e) in failed.iter() {
+        eprintln!("error: unable to refresh {}: {:?}", name, e);
+    }
+
+    std::io::stdout().write_all(&provider.gather(format)?)?;
+    if !failed.is_empty() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use anyhow::{Context, Error};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use zeroize::Zeroize;
+
+/// A credential (token, password) that never shows up in logs.
+///
+/// `Debug` and `Display` print `***`, the memory is zeroed when the value is
+/// dropped and the actual value is only available through `expose_secret`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn expose_secret(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// A secret passed either directly in an environment variable or in a file
+/// (e.g. a mounted docker secret) named by the `_FILE` variant of that
+/// variable. Files are re-read whenever they change, so secrets can be
+/// rotated without restarting monitorbot.
+#[derive(Clone, Debug)]
+pub enum SecretSource {
+    Value(Secret),
+    File(Arc<SecretFile>),
+}
+
+#[derive(Debug)]
+pub struct SecretFile {
+    path: PathBuf,
+    // content of the file along with the modification time and length it was read at
+    cache: Mutex<Option<(SystemTime, u64, Secret)>>,
+}
+
+impl SecretSource {
+    pub fn file(path: impl Into<PathBuf>) -> Self {
+        Self::File(Arc::new(SecretFile {
+            path: path.into(),
+            cache: Mutex::new(None),
+        }))
+    }
+
+    /// Current value of the secret, re-reading the file if it changed since
+    /// the last time it was read.
+    pub fn get(&self) -> Result<Secret, Error> {
+        match self {
+            Self::Value(value) => Ok(value.clone()),
+            Self::File(file) => file.read(),
+        }
+    }
+}
+
+impl SecretFile {
+    fn read(&self) -> Result<Secret, Error> {
+        let metadata = std::fs::metadata(&self.path)
+            .with_context(|| format!("unable to access secret file {}", self.path.display()))?;
+        let modified = metadata.modified()?;
+
+        let mut cache = self.cache.lock().unwrap();
+        match &*cache {
+            Some((m, len, value)) if *m == modified && *len == metadata.len() => Ok(value.clone()),
+            _ => {
+                let content =
+                    Secret::new(std::fs::read_to_string(&self.path).with_context(|| {
+                        format!("unable to read secret file {}", self.path.display())
+                    })?);
+                let value = Secret::new(content.expose_secret().trim().to_string());
+                *cache = Some((modified, metadata.len(), value.clone()));
+                Ok(value)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Secret, SecretSource};
+
+    #[test]
+    fn secret_is_redacted() {
+        let secret = Secret::new("ghp_FAKETOKEN".to_string());
+        assert_eq!("***", format!("{}", secret));
+        assert_eq!("Value(***)", format!("{:?}", SecretSource::Value(secret)));
+    }
+}
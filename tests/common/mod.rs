@@ -0,0 +1,453 @@
+//! Shared helpers for the integration tests: a local stand-in for the GitHub
+//! API with scripted responses and a way to run the real collectors against it.
+
+#![allow(dead_code)]
+
+use hyper::header::AUTHORIZATION;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use monitorbot::collectors::register_collectors;
+use monitorbot::encoding::Format;
+use monitorbot::{Config, MetricProvider, Secret, SecretSource};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A scripted response of the mock server.
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl MockResponse {
+    /// A JSON response with plenty of rate limit left.
+    pub fn json(status: u16, body: serde_json::Value) -> Self {
+        Self::raw(status, &body.to_string())
+            .header("content-type", "application/json")
+            .header("x-ratelimit-remaining", "4999")
+    }
+
+    pub fn raw(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.retain(|(n, _)| n != name);
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+// responses are keyed by the token they are for (`None` matches any token)
+// and the path and query of the request
+type Routes = HashMap<(Option<String>, String), VecDeque<MockResponse>>;
+
+/// Local HTTP server standing in for the GitHub API.
+///
+/// Responses pushed for a route are returned in order, the last one is then
+/// repeated for every following request. Unknown routes return 404.
+pub struct MockGitHub {
+    addr: SocketAddr,
+    routes: Arc<Mutex<Routes>>,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockGitHub {
+    pub async fn start() -> Self {
+        let routes: Arc<Mutex<Routes>> = Arc::default();
+        let requests: Arc<Mutex<Vec<String>>> = Arc::default();
+
+        let (routes2, requests2) = (routes.clone(), requests.clone());
+        let make_svc = make_service_fn(move |_| {
+            let (routes, requests) = (routes2.clone(), requests2.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let response = respond(&routes, &requests, req);
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        Self {
+            addr,
+            routes,
+            requests,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Scripts a response for `path` (including the query) for every token.
+    pub fn push(&self, path: &str, response: MockResponse) {
+        self.push_route(None, path, response);
+    }
+
+    /// Scripts a response for `path` (including the query) for one token.
+    pub fn push_for_token(&self, token: &str, path: &str, response: MockResponse) {
+        self.push_route(Some(token), path, response);
+    }
+
+    fn push_route(&self, token: Option<&str>, path: &str, response: MockResponse) {
+        self.routes
+            .lock()
+            .unwrap()
+            .entry((token.map(String::from), path.to_string()))
+            .or_default()
+            .push_back(response);
+    }
+
+    /// Paths (including the query) of every request received so far.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn respond(
+    routes: &Mutex<Routes>,
+    requests: &Mutex<Vec<String>>,
+    req: Request<Body>,
+) -> Response<Body> {
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or_else(|| req.uri().path().to_string(), |pq| pq.to_string());
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("token "))
+        .map(String::from);
+    requests.lock().unwrap().push(path.clone());
+
+    let mut routes = routes.lock().unwrap();
+    let queue = match routes.get_mut(&(token, path.clone())) {
+        Some(queue) => Some(queue),
+        None => routes.get_mut(&(None, path)),
+    };
+    let scripted = queue.and_then(|queue| {
+        if queue.len() > 1 {
+            queue.pop_front()
+        } else {
+            queue.front().cloned()
+        }
+    });
+
+    let scripted =
+        scripted.unwrap_or_else(|| MockResponse::raw(404, "{\"message\":\"Not Found\"}"));
+    let mut builder = Response::builder().status(scripted.status);
+    for (name, value) in scripted.headers.iter() {
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+    builder.body(Body::from(scripted.body)).unwrap()
+}
+
+/// Configuration pointing every GitHub collector at `api_url`.
+pub fn config(api_url: &str, rate_limit_tokens: &str, runners_repos: &str) -> Config {
+    Config {
+        secret: SecretSource::Value(Secret::new("secret".to_string())),
+        port: 0,
+        gh_rate_limit_tokens: SecretSource::Value(Secret::new(rate_limit_tokens.to_string())),
+        gh_rate_limit_stats_cache_refresh: 120,
+        github_token: SecretSource::Value(Secret::new("runners-token".to_string())),
+        gha_runners_repos: runners_repos.to_string(),
+        gha_runners_cache_refresh: 120,
+        gha_runners_deregistered_retention: 3600,
+        github_api_url: api_url.to_string(),
+        http_record_dir: None,
+        http_replay_dir: None,
+        series_ttl: 600,
+        alert_rules: String::new(),
+        alert_webhooks: None,
+        state_dir: None,
+        http_probe_targets: String::new(),
+        http_probe_refresh: 60,
+        http_probe_timeout: 10,
+        tls_targets: String::new(),
+        tls_refresh: 3600,
+        tls_timeout: 10,
+        dns_records: String::new(),
+        dns_resolver: ([127, 0, 0, 1], 53).into(),
+        dns_refresh: 300,
+        dns_timeout: 5,
+        rust_dist_url: "https://static.rust-lang.org/dist".to_string(),
+        rust_channels: String::new(),
+        rust_channels_targets: "x86_64-unknown-linux-gnu,aarch64-apple-darwin".to_string(),
+        rust_channels_components: "rustc,cargo,rustfmt,clippy".to_string(),
+        rust_channels_refresh: 600,
+        crates_io_api_url: "https://crates.io/api/v1".to_string(),
+        crates_index_url: String::new(),
+        crates_index_git_url: String::new(),
+        crates_index_refresh: 60,
+        docsrs_url: String::new(),
+        docsrs_refresh: 60,
+        bors_url: "https://bors.rust-lang.org".to_string(),
+        bors_repos: String::new(),
+        bors_refresh: 60,
+        webhook_repos: String::new(),
+        webhook_orgs: String::new(),
+        webhook_deliveries: 100,
+        webhook_refresh: 300,
+        gha_workflows: String::new(),
+        gha_workflow_runs: 50,
+        gha_workflows_refresh: 300,
+    }
+}
+
+/// Starts a local TLS server presenting a `localhost` certificate issued by a
+/// test CA, along with the CA certificate. Returns the port it listens on.
+pub fn start_tls_server() -> u16 {
+    use rcgen::{
+        date_time_ymd, BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa,
+        KeyPair, SanType,
+    };
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.distinguished_name = DistinguishedName::new();
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "monitorbot test CA");
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params.not_after = date_time_ymd(2035, 1, 1);
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+
+    let key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "localhost");
+    params
+        .subject_alt_names
+        .push(SanType::IpAddress([127, 0, 0, 1].into()));
+    params.not_after = date_time_ymd(2030, 1, 1);
+    let leaf = params.signed_by(&key, &ca, &ca_key).unwrap();
+
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_no_client_auth()
+    .with_single_cert(
+        vec![leaf.der().clone(), ca.der().clone()],
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())),
+    )
+    .unwrap();
+    let config = Arc::new(config);
+
+    let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let (mut stream, config) = match stream {
+                Ok(stream) => (stream, config.clone()),
+                Err(_) => continue,
+            };
+            std::thread::spawn(move || {
+                let mut conn = rustls::ServerConnection::new(config).unwrap();
+                while conn.is_handshaking() {
+                    if conn.complete_io(&mut stream).is_err() {
+                        return;
+                    }
+                }
+                conn.send_close_notify();
+                let _ = conn.complete_io(&mut stream);
+            });
+        }
+    });
+    port
+}
+
+/// Starts a local DNS resolver answering with the given `(name, type, value)`
+/// records, and with NXDOMAIN for names without any record.
+pub fn start_dns_server(records: &[(&str, &str, &str)]) -> SocketAddr {
+    use hickory_proto::op::{Message, MessageType, ResponseCode};
+    use hickory_proto::rr::rdata::{A, AAAA, CNAME};
+    use hickory_proto::rr::{Name, RData, Record, RecordType};
+    use std::str::FromStr;
+
+    let records: Vec<Record> = records
+        .iter()
+        .map(|(name, record_type, value)| {
+            let data = match RecordType::from_str(record_type).unwrap() {
+                RecordType::A => RData::A(A(value.parse().unwrap())),
+                RecordType::AAAA => RData::AAAA(AAAA(value.parse().unwrap())),
+                RecordType::CNAME => RData::CNAME(CNAME(Name::from_ascii(value).unwrap())),
+                other => panic!("unsupported record type {}", other),
+            };
+            Record::from_rdata(Name::from_ascii(name).unwrap(), 300, data)
+        })
+        .collect();
+
+    let socket = std::net::UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    let addr = socket.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        while let Ok((len, peer)) = socket.recv_from(&mut buffer) {
+            let request = match Message::from_vec(&buffer[..len]) {
+                Ok(request) => request,
+                Err(_) => continue,
+            };
+            let query = request.queries()[0].clone();
+            let known = records.iter().any(|r| r.name() == query.name());
+            let answers = records
+                .iter()
+                .filter(|r| r.name() == query.name() && r.record_type() == query.query_type())
+                .cloned();
+
+            let mut response = Message::new();
+            response
+                .set_id(request.id())
+                .set_message_type(MessageType::Response)
+                .set_response_code(match known {
+                    true => ResponseCode::NoError,
+                    false => ResponseCode::NXDomain,
+                })
+                .add_query(query.clone())
+                .add_answers(answers);
+            let _ = socket.send_to(&response.to_vec().unwrap(), peer);
+        }
+    });
+    addr
+}
+
+/// Outcome of refreshing collectors once.
+pub struct Collected {
+    // text exposition of the gathered metrics
+    pub output: String,
+    // names of the collectors that failed along with their error
+    pub failed: Vec<(String, String)>,
+}
+
+/// Registers the named collectors, refreshes them once and gathers them.
+pub async fn collect(config: Config, collectors: &[&str]) -> Collected {
+    collect_refreshed(config, collectors, 1).await
+}
+
+/// Like `collect`, but refreshes the collectors `refreshes` times, the
+/// failures are the ones of the last refresh.
+pub async fn collect_refreshed(config: Config, collectors: &[&str], refreshes: usize) -> Collected {
+    let provider = MetricProvider::new(config);
+    let only: Vec<String> = collectors.iter().map(|c| c.to_string()).collect();
+    let registered = register_collectors(&provider, &only)
+        .await
+        .expect("unable to register collectors");
+
+    let mut failed = Vec::new();
+    for _ in 0..refreshes {
+        failed = registered
+            .refresh_once()
+            .await
+            .into_iter()
+            .map(|(name, e)| (name.to_string(), format!("{:#}", e)))
+            .collect();
+    }
+    let output = provider.gather(Format::Text).unwrap();
+
+    Collected {
+        output: String::from_utf8(output).unwrap(),
+        failed,
+    }
+}
+
+/// Directory of the recorded GitHub API fixtures, see `tests/replay.rs`.
+pub fn github_fixtures() -> PathBuf {
+    fixtures("github")
+}
+
+/// Directory of the recorded fixtures of a collector in `tests/fixtures`.
+pub fn fixtures(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+/// Configuration replaying the recorded GitHub API fixtures.
+pub fn replay_config() -> Config {
+    let mut config = config("https://api.github.com", "token-1", "rust-lang/rust");
+    config.github_token = SecretSource::Value(Secret::new("token-2".to_string()));
+    config.http_replay_dir = Some(github_fixtures());
+    config
+}
+
+pub fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.prom", name))
+}
+
+/// Compares `actual` to the checked-in golden file `tests/golden/{name}.prom`.
+///
+/// Run the tests with `UPDATE_GOLDEN=1` to overwrite the golden files with
+/// the current output when a change is intended.
+pub fn assert_golden(name: &str, actual: &str) {
+    assert_file(&golden_path(name), actual);
+}
+
+/// Replaces the values of `metrics` in a text exposition by `<masked>`, for
+/// values that change on every run such as durations.
+pub fn mask_values(output: &str, metrics: &[&str]) -> String {
+    output
+        .lines()
+        .map(|line| {
+            let name = line.split(['{', ' ']).next().unwrap_or("");
+            match line.rsplit_once(' ') {
+                Some((series, _)) if metrics.contains(&name) => format!("{} <masked>\n", series),
+                _ => format!("{}\n", line),
+            }
+        })
+        .collect()
+}
+
+/// Compares `actual` to the checked-in file at `path`, overwriting the file
+/// instead when running with `UPDATE_GOLDEN=1`.
+pub fn assert_file(path: &Path, actual: &str) {
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(path, actual).unwrap();
+        return;
+    }
+
+    let expected = std::fs::read_to_string(path).unwrap_or_else(|_| {
+        panic!(
+            "missing golden file {}, run the tests with UPDATE_GOLDEN=1 to create it",
+            path.display()
+        )
+    });
+    if expected != actual {
+        let mut diff = String::new();
+        for line in expected
+            .lines()
+            .filter(|l| !actual.lines().any(|a| a == *l))
+        {
+            diff.push_str(&format!("-{}\n", line));
+        }
+        for line in actual
+            .lines()
+            .filter(|l| !expected.lines().any(|e| e == *l))
+        {
+            diff.push_str(&format!("+{}\n", line));
+        }
+        panic!(
+            "output doesn't match the golden file {}:\n{}\n\
+             if the change is intended, run the tests with UPDATE_GOLDEN=1",
+            path.display(),
+            diff
+        );
+    }
+}
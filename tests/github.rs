@@ -0,0 +1,308 @@
+mod common;
+
+use common::{collect, collect_refreshed, config, MockGitHub, MockResponse};
+use serde_json::json;
+
+fn rate_limit_body(remaining: i64) -> serde_json::Value {
+    json!({
+        "resources": {
+            "core": { "limit": 5000, "remaining": remaining, "reset": 1600000000, "used": 5000 - remaining },
+            "search": { "limit": 30, "remaining": 30, "reset": 1600000000, "used": 0 },
+        }
+    })
+}
+
+fn runner(id: usize, name: &str, status: &str, busy: bool) -> serde_json::Value {
+    json!({ "id": id, "name": name, "os": "linux", "status": status, "busy": busy })
+}
+
+#[tokio::test]
+async fn rate_limit_isolates_failing_tokens() {
+    let github = MockGitHub::start().await;
+    github.push_for_token(
+        "good",
+        "/user",
+        MockResponse::json(200, json!({ "login": "good-user" })),
+    );
+    github.push_for_token(
+        "good",
+        "/rate_limit",
+        MockResponse::json(200, rate_limit_body(4000)),
+    );
+    github.push_for_token("bad", "/rate_limit", MockResponse::raw(200, "{ not json"));
+    github.push_for_token("down", "/rate_limit", MockResponse::raw(502, "Bad Gateway"));
+
+    let collected = collect(
+        config(&github.url(), "good,bad-alias=bad,down-alias=down", ""),
+        &["github_rate_limit"],
+    )
+    .await;
+
+    let output = &collected.output;
+    assert!(output.contains(
+        "monitorbot_github_rate_limit_remaining{product=\"core\",username=\"good-user\"} 4000"
+    ));
+    assert!(output.contains(
+        "monitorbot_github_rate_limit_remaining{product=\"search\",username=\"good-user\"} 30"
+    ));
+    assert!(
+        output.contains("monitorbot_github_rate_limit_scrape_success{username=\"good-user\"} 1")
+    );
+    assert!(
+        output.contains("monitorbot_github_rate_limit_scrape_success{username=\"bad-alias\"} 0")
+    );
+    assert!(
+        output.contains("monitorbot_github_rate_limit_scrape_success{username=\"down-alias\"} 0")
+    );
+    assert!(!output.contains("product=\"core\",username=\"bad-alias\""));
+
+    assert_eq!(1, collected.failed.len());
+    assert!(collected.failed[0].1.contains("2 of 3 tokens"));
+}
+
+#[tokio::test]
+async fn rate_limit_skips_revoked_tokens_at_startup() {
+    let github = MockGitHub::start().await;
+    github.push_for_token(
+        "good",
+        "/user",
+        MockResponse::json(200, json!({ "login": "good-user" })),
+    );
+    github.push_for_token(
+        "good",
+        "/rate_limit",
+        MockResponse::json(200, rate_limit_body(4000)),
+    );
+    github.push_for_token(
+        "revoked",
+        "/user",
+        MockResponse::json(401, json!({ "message": "Bad credentials" })),
+    );
+
+    let collected = collect(
+        config(&github.url(), "good,revoked", ""),
+        &["github_rate_limit"],
+    )
+    .await;
+
+    assert!(collected.failed.is_empty(), "{:?}", collected.failed);
+    assert!(collected
+        .output
+        .contains("monitorbot_github_rate_limit_scrape_success{username=\"good-user\"} 1"));
+}
+
+#[tokio::test]
+async fn runners_follow_pagination() {
+    let github = MockGitHub::start().await;
+    let next = format!(
+        "<{}/repos/rust-lang/rust/actions/runners?per_page=100&page=2>; rel=\"next\"",
+        github.url()
+    );
+    github.push(
+        "/repos/rust-lang/rust/actions/runners?per_page=100",
+        MockResponse::json(
+            200,
+            json!({ "total_count": 2, "runners": [runner(1, "aws-1", "online", true)] }),
+        )
+        .header("link", &next),
+    );
+    github.push(
+        "/repos/rust-lang/rust/actions/runners?per_page=100&page=2",
+        MockResponse::json(
+            200,
+            json!({ "total_count": 2, "runners": [runner(2, "aws-2", "offline", false)] }),
+        ),
+    );
+
+    let collected = collect(
+        config(&github.url(), "", "rust-lang/rust"),
+        &["github_runners"],
+    )
+    .await;
+
+    assert!(collected.failed.is_empty(), "{:?}", collected.failed);
+    let output = &collected.output;
+    assert!(
+        output.contains("monitorbot_gha_runner_online{repo=\"rust-lang/rust\",runner=\"aws-1\"} 1")
+    );
+    assert!(
+        output.contains("monitorbot_gha_runner_busy{repo=\"rust-lang/rust\",runner=\"aws-1\"} 1")
+    );
+    assert!(
+        output.contains("monitorbot_gha_runner_online{repo=\"rust-lang/rust\",runner=\"aws-2\"} 0")
+    );
+    assert_eq!(
+        vec![
+            "/repos/rust-lang/rust/actions/runners?per_page=100",
+            "/repos/rust-lang/rust/actions/runners?per_page=100&page=2",
+        ],
+        github.requests()
+    );
+}
+
+#[tokio::test]
+async fn runners_rate_limited() {
+    let github = MockGitHub::start().await;
+    github.push(
+        "/repos/rust-lang/rust/actions/runners?per_page=100",
+        MockResponse::json(403, json!({ "message": "API rate limit exceeded" }))
+            .header("x-ratelimit-remaining", "0"),
+    );
+
+    let collected = collect(
+        config(&github.url(), "", "rust-lang/rust"),
+        &["github_runners"],
+    )
+    .await;
+
+    assert_eq!(1, collected.failed.len());
+    assert!(collected.failed[0].1.contains("We've hit the rate limit"));
+    assert!(!collected.output.contains("gha_runner"));
+}
+
+#[tokio::test]
+async fn runners_server_error_and_malformed_json() {
+    let github = MockGitHub::start().await;
+    github.push(
+        "/repos/rust-lang/rust/actions/runners?per_page=100",
+        MockResponse::raw(503, "Service Unavailable"),
+    );
+    github.push(
+        "/repos/rust-lang/cargo/actions/runners?per_page=100",
+        MockResponse::raw(200, "{ \"runners\": ").header("x-ratelimit-remaining", "4999"),
+    );
+
+    let collected = collect(
+        config(&github.url(), "", "rust-lang/rust"),
+        &["github_runners"],
+    )
+    .await;
+    assert_eq!(1, collected.failed.len());
+    assert!(collected.failed[0].1.contains("503"));
+
+    let collected = collect(
+        config(&github.url(), "", "rust-lang/cargo"),
+        &["github_runners"],
+    )
+    .await;
+    assert_eq!(1, collected.failed.len());
+    assert!(!collected.output.contains("gha_runner"));
+}
+
+fn hook(id: u64, url: &str) -> serde_json::Value {
+    json!({ "id": id, "config": { "url": url }, "last_response": { "code": 200 } })
+}
+
+#[tokio::test]
+async fn webhooks_isolate_failing_hooks_and_targets() {
+    let github = MockGitHub::start().await;
+    github.push(
+        "/repos/rust-lang/rust/hooks?per_page=100",
+        MockResponse::json(
+            200,
+            json!([
+                hook(1, "https://bors.rust-lang.org/github"),
+                hook(2, "https://triagebot.infra.rust-lang.org/github-hook")
+            ]),
+        ),
+    );
+    github.push(
+        "/repos/rust-lang/rust/hooks/1/deliveries?per_page=100",
+        MockResponse::raw(502, "Bad Gateway"),
+    );
+    github.push(
+        "/repos/rust-lang/rust/hooks/2/deliveries?per_page=100",
+        MockResponse::json(200, json!([{ "guid": "a", "status_code": 200 }])),
+    );
+    github.push(
+        "/repos/rust-lang/cargo/hooks?per_page=100",
+        MockResponse::raw(502, "Bad Gateway"),
+    );
+    github.push(
+        "/orgs/rust-lang/hooks?per_page=100",
+        MockResponse::json(200, json!([hook(3, "https://bors.rust-lang.org/github")])),
+    );
+    github.push(
+        "/orgs/rust-lang/hooks/3/deliveries?per_page=100",
+        MockResponse::json(200, json!([{ "guid": "b", "status_code": 500 }])),
+    );
+
+    let mut config = config(&github.url(), "", "");
+    config.webhook_repos = "rust-lang/rust,rust-lang/cargo".to_string();
+    config.webhook_orgs = "rust-lang".to_string();
+    let collected = collect(config, &["github_webhooks"]).await;
+
+    assert_eq!(1, collected.failed.len());
+    assert!(collected.failed[0].1.contains("2 of 3 targets"));
+    let output = &collected.output;
+    assert!(!output.contains("hook=\"1\""));
+    assert!(output.contains(
+        "monitorbot_github_webhook_recent_deliveries{hook=\"2\",target=\"rust-lang/rust\",url=\"https://triagebot.infra.rust-lang.org\"} 1"
+    ));
+    assert!(output.contains(
+        "monitorbot_github_webhook_failed_deliveries{hook=\"3\",target=\"rust-lang\",url=\"https://bors.rust-lang.org\"} 1"
+    ));
+}
+
+#[tokio::test]
+async fn webhooks_follow_url_changes() {
+    let github = MockGitHub::start().await;
+    github.push(
+        "/repos/rust-lang/rust/hooks?per_page=100",
+        MockResponse::json(200, json!([hook(1, "https://bors.rust-lang.org/github")])),
+    );
+    github.push(
+        "/repos/rust-lang/rust/hooks?per_page=100",
+        MockResponse::json(200, json!([hook(1, "https://bors.example.com/github")])),
+    );
+    github.push(
+        "/repos/rust-lang/rust/hooks/1/deliveries?per_page=100",
+        MockResponse::json(200, json!([])),
+    );
+
+    let mut config = config(&github.url(), "", "");
+    config.webhook_repos = "rust-lang/rust".to_string();
+    let collected = collect_refreshed(config, &["github_webhooks"], 2).await;
+
+    assert!(collected.failed.is_empty(), "{:?}", collected.failed);
+    assert!(collected
+        .output
+        .contains("url=\"https://bors.example.com\""));
+    assert!(!collected
+        .output
+        .contains("url=\"https://bors.rust-lang.org\""));
+}
+
+#[tokio::test]
+async fn workflows_forget_the_last_success_once_gone() {
+    let github = MockGitHub::start().await;
+    let runs = "/repos/rust-lang/rust/actions/workflows/ci.yml/runs?branch=master";
+    let run = json!({
+        "conclusion": "success",
+        "run_started_at": "2024-06-21T10:00:00Z",
+        "updated_at": "2024-06-21T12:00:00Z",
+    });
+    github.push(
+        &format!("{}&status=completed&per_page=50", runs),
+        MockResponse::json(200, json!({ "workflow_runs": [run] })),
+    );
+    github.push(
+        &format!("{}&status=success&per_page=1", runs),
+        MockResponse::json(200, json!({ "workflow_runs": [run] })),
+    );
+    // the runs of the branch are gone on the second refresh
+    github.push(
+        &format!("{}&status=success&per_page=1", runs),
+        MockResponse::json(200, json!({ "workflow_runs": [] })),
+    );
+
+    let mut config = config(&github.url(), "", "");
+    config.gha_workflows = "rust-lang/rust/ci.yml@master".to_string();
+    let collected = collect_refreshed(config, &["github_workflows"], 2).await;
+
+    assert!(collected.failed.is_empty(), "{:?}", collected.failed);
+    assert!(collected
+        .output
+        .contains("monitorbot_gha_workflow_runs{branch=\"master\",conclusion=\"success\""));
+    assert!(!collected.output.contains("gha_workflow_last_success"));
+}
@@ -0,0 +1,234 @@
+//! Golden file tests of the exposition output of every collector, so that
+//! metrics (which alerts and dashboards depend on) can't be renamed silently.
+//!
+//! Run `UPDATE_GOLDEN=1 cargo test --test golden` to update the golden files
+//! in `tests/golden` after an intended change.
+
+mod common;
+
+use common::{
+    assert_file, assert_golden, collect, fixtures, golden_path, mask_values, replay_config,
+    start_dns_server, start_tls_server,
+};
+use monitorbot::catalog::{render, CatalogFormat};
+use monitorbot::collectors::COLLECTORS;
+use monitorbot::generate::{grafana_dashboard, prometheus_rules, DASHBOARD_FILE, RULES_FILE};
+use monitorbot::Config;
+use std::path::PathBuf;
+
+/// Collects `collector` once with the replay configuration, answering from
+/// `tests/fixtures/{fixtures}` if given, after applying `configure`.
+async fn collect_replayed(
+    collector: &str,
+    fixtures_dir: Option<&str>,
+    configure: impl FnOnce(&mut Config),
+) -> String {
+    let mut config = replay_config();
+    if let Some(dir) = fixtures_dir {
+        config.http_replay_dir = Some(fixtures(dir));
+    }
+    configure(&mut config);
+    let collected = collect(config, &[collector]).await;
+    assert!(collected.failed.is_empty(), "{:?}", collected.failed);
+    collected.output
+}
+
+/// Compares the output of `collector` (see `collect_replayed`) to its golden
+/// file, with the values of the `masked` metrics masked.
+async fn assert_collector_golden(
+    collector: &str,
+    fixtures_dir: Option<&str>,
+    configure: impl FnOnce(&mut Config),
+    masked: &[&str],
+) {
+    let output = collect_replayed(collector, fixtures_dir, configure).await;
+    assert_golden(collector, &mask_values(&output, masked));
+}
+
+#[tokio::test]
+async fn github_rate_limit() {
+    assert_collector_golden("github_rate_limit", None, |_| {}, &[]).await;
+}
+
+#[tokio::test]
+async fn github_runners() {
+    assert_collector_golden(
+        "github_runners",
+        None,
+        |_| {},
+        &["monitorbot_gha_runner_offline_seconds"],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn http_probe() {
+    assert_collector_golden(
+        "http_probe",
+        Some("http_probe"),
+        |config| {
+            // the last target has no fixture, so it's down
+            config.http_probe_targets = "https://crates.io/api/v1/summary body=num_crates, \
+                https://www.rust-lang.org/ header=content-type:text/html body=Rust, \
+                https://play.rust-lang.org/health"
+                .to_string();
+        },
+        &["monitorbot_http_probe_duration_seconds"],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn tls_certs() {
+    let port = start_tls_server().to_string();
+    let output = collect_replayed("tls_certs", None, |config| {
+        // nothing listens on port 1, so the handshake fails
+        config.tls_targets = format!("localhost:{}, 127.0.0.1:1", port);
+    })
+    .await;
+    let output = mask_values(&output, &["monitorbot_tls_cert_days_until_expiry"]);
+    assert_golden("tls_certs", &output.replace(&port, "PORT"));
+}
+
+#[tokio::test]
+async fn dns_records() {
+    let resolver = start_dns_server(&[
+        ("crates.io", "A", "13.32.1.1"),
+        ("crates.io", "A", "13.32.1.2"),
+        ("static.rust-lang.org", "CNAME", "d1.cloudfront.net"),
+        ("docs.rs", "A", "6.6.6.6"),
+    ]);
+    assert_collector_golden(
+        "dns_records",
+        None,
+        |config| {
+            // docs.rs resolves to an unexpected address and doc.rust-lang.org doesn't resolve
+            config.dns_resolver = resolver;
+            config.dns_records = "crates.io A=13.32.1.1|13.32.1.2|13.32.1.3, \
+                static.rust-lang.org CNAME=d1.cloudfront.net., docs.rs A=13.32.2.1, \
+                doc.rust-lang.org AAAA"
+                .to_string();
+        },
+        &["monitorbot_dns_resolution_duration_seconds"],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn rust_channels() {
+    assert_collector_golden(
+        "rust_channels",
+        Some("rust_channels"),
+        |config| {
+            // the beta is missing clippy on aarch64-apple-darwin, the nightly is
+            // missing clippy everywhere and rustfmt on aarch64-apple-darwin
+            config.rust_channels = "nightly,beta,stable".to_string();
+        },
+        &["monitorbot_rust_channel_manifest_age_seconds"],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn crates_index() {
+    assert_collector_golden(
+        "crates_index",
+        Some("crates_index"),
+        |config| {
+            // the new tokio version and the new crate aren't in the sparse index yet
+            config.crates_index_url = "https://index.crates.io".to_string();
+            config.crates_index_git_url =
+                "https://github.com/rust-lang/crates.io-index".to_string();
+        },
+        &[
+            "monitorbot_crates_index_newest_version_age_seconds",
+            "monitorbot_crates_index_fetch_duration_seconds",
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn docs_rs() {
+    assert_collector_golden(
+        "docs_rs",
+        Some("docs_rs"),
+        |config| config.docsrs_url = "https://docs.rs".to_string(),
+        &[
+            "monitorbot_docsrs_queue_oldest_age_seconds",
+            "monitorbot_docsrs_last_build_age_seconds",
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn bors_queue() {
+    assert_collector_golden(
+        "bors_queue",
+        Some("bors"),
+        |config| config.bors_repos = "rust-lang/rust,rust-lang/cargo".to_string(),
+        &[
+            "monitorbot_bors_testing_duration_seconds",
+            "monitorbot_bors_last_merge_age_seconds",
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn github_webhooks() {
+    assert_collector_golden(
+        "github_webhooks",
+        Some("github_webhooks"),
+        |config| {
+            config.webhook_repos = "rust-lang/rust".to_string();
+            config.webhook_orgs = "rust-lang".to_string();
+        },
+        &[],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn github_workflows() {
+    assert_collector_golden(
+        "github_workflows",
+        Some("github_workflows"),
+        |config| {
+            config.gha_workflows =
+                "rust-lang/rust/ci.yml@master,rust-lang/cargo/main.yml@master".to_string();
+        },
+        &["monitorbot_gha_workflow_last_success_age_seconds"],
+    )
+    .await;
+}
+
+#[test]
+fn every_collector_has_a_golden_file() {
+    for collector in COLLECTORS {
+        assert!(
+            golden_path(collector.name).exists(),
+            "collector {} has no golden file test",
+            collector.name
+        );
+    }
+}
+
+#[test]
+fn metrics_documentation_is_up_to_date() {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("docs/metrics.md");
+    let docs = format!(
+        "# Metrics\n\n\
+         <!-- generated by `monitorbot catalog`, update with `UPDATE_GOLDEN=1 cargo test --test golden` -->\n\n{}",
+        render(CatalogFormat::Markdown).unwrap()
+    );
+    assert_file(&path, &docs);
+}
+
+#[test]
+fn generated_rules_and_dashboard() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    assert_file(&dir.join(RULES_FILE), &prometheus_rules().unwrap());
+    assert_file(&dir.join(DASHBOARD_FILE), &grafana_dashboard().unwrap());
+}
@@ -0,0 +1,110 @@
+mod common;
+
+use common::{collect, config, replay_config, MockGitHub, MockResponse};
+use serde_json::json;
+
+#[tokio::test]
+async fn replay_recorded_github_responses() {
+    let collected = collect(replay_config(), &[]).await;
+    assert!(collected.failed.is_empty(), "{:?}", collected.failed);
+
+    let output = &collected.output;
+    assert!(output.contains(
+        "monitorbot_github_rate_limit_remaining{product=\"core\",username=\"user-1\"} 4987"
+    ));
+    assert!(output.contains(
+        "monitorbot_github_rate_limit_used{product=\"code_search\",username=\"user-1\"} 10"
+    ));
+    assert!(output.contains(
+        "monitorbot_github_rate_limit_exhausted_total{product=\"code_search\",username=\"user-1\"} 1"
+    ));
+    assert!(output
+        .contains("monitorbot_gha_runner_busy{repo=\"rust-lang/rust\",runner=\"aws-x86_64-1\"} 1"));
+    assert!(output.contains(
+        "monitorbot_gha_runner_online{repo=\"rust-lang/rust\",runner=\"aws-aarch64-1\"} 0"
+    ));
+}
+
+#[tokio::test]
+async fn recorded_fixtures_are_scrubbed_and_replayable() {
+    let github = MockGitHub::start().await;
+    github.push(
+        "/user",
+        MockResponse::json(
+            200,
+            json!({ "login": "real-login", "email": "someone@example.com", "name": "Some One" }),
+        ),
+    );
+    github.push(
+        "/rate_limit",
+        MockResponse::json(
+            200,
+            json!({
+                "resources": { "core": { "limit": 5000, "remaining": 4000, "reset": 1700000000, "used": 1000 } },
+                "note": "requested by real-login with ghp_realtoken",
+            }),
+        ),
+    );
+
+    let dir = std::env::temp_dir().join(format!("monitorbot-record-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let mut recording = config(&github.url(), "ghp_realtoken", "");
+    recording.http_record_dir = Some(dir.clone());
+    let recorded = collect(recording, &["github_rate_limit"]).await;
+    assert!(recorded.failed.is_empty(), "{:?}", recorded.failed);
+
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let content = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+        assert!(!content.contains("ghp_realtoken"), "{}", content);
+        assert!(!content.contains("real-login"), "{}", content);
+        assert!(!content.contains("someone@example.com"), "{}", content);
+    }
+
+    // fixtures are matched on the host too, the mock server is not queried
+    let mut replaying = config(&github.url(), "token-1", "");
+    replaying.http_replay_dir = Some(dir.clone());
+    let replayed = collect(replaying, &["github_rate_limit"]).await;
+    assert!(replayed.failed.is_empty(), "{:?}", replayed.failed);
+    assert_eq!(
+        recorded.output.replace("real-login", "user-1"),
+        replayed.output
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn github_enterprise_users_are_scrubbed() {
+    let github = MockGitHub::start().await;
+    github.push(
+        "/api/v3/user",
+        MockResponse::json(
+            200,
+            json!({ "login": "real-login", "email": "someone@example.com" }),
+        ),
+    );
+    github.push(
+        "/api/v3/rate_limit",
+        MockResponse::json(
+            200,
+            json!({ "resources": { "core": { "limit": 5000, "remaining": 4000, "reset": 1700000000, "used": 1000 } } }),
+        ),
+    );
+
+    let dir = std::env::temp_dir().join(format!("monitorbot-record-ghe-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let mut recording = config(&format!("{}/api/v3", github.url()), "ghp_realtoken", "");
+    recording.http_record_dir = Some(dir.clone());
+    let recorded = collect(recording, &["github_rate_limit"]).await;
+    assert!(recorded.failed.is_empty(), "{:?}", recorded.failed);
+
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let content = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+        assert!(!content.contains("real-login"), "{}", content);
+        assert!(!content.contains("someone@example.com"), "{}", content);
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,28 @@
+mod common;
+
+use common::{replay_config, start_tls_server};
+use monitorbot::collectors::register_collectors;
+use monitorbot::encoding::Format;
+use monitorbot::MetricProvider;
+use std::time::Duration;
+
+#[tokio::test]
+async fn certificates_are_reported_between_refreshes() {
+    let mut config = replay_config();
+    config.tls_targets = format!("localhost:{}", start_tls_server());
+    // the series ttl elapses long before the next refresh
+    config.series_ttl = 1;
+    config.tls_refresh = 3600;
+
+    let provider = MetricProvider::new(config);
+    let registered = register_collectors(&provider, &["tls_certs".to_string()])
+        .await
+        .expect("unable to register collectors");
+    let failed = registered.refresh_once().await;
+    assert!(failed.is_empty());
+
+    tokio::time::delay_for(Duration::from_secs(2)).await;
+    let output = String::from_utf8(provider.gather(Format::Text).unwrap()).unwrap();
+    assert!(output.contains("monitorbot_tls_cert_days_until_expiry{depth=\"0\""));
+    assert!(output.contains("monitorbot_tls_cert_verified{"));
+}
@@ -0,0 +1,47 @@
+mod common;
+
+use common::{collect, config, replay_config, MockGitHub, MockResponse};
+use serde_json::json;
+
+#[tokio::test]
+async fn snapshots_are_restored_and_marked_stale() {
+    let dir = std::env::temp_dir().join(format!("monitorbot-state-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let mut first = replay_config();
+    first.state_dir = Some(dir.clone());
+    let collected = collect(first, &[]).await;
+    assert!(collected.failed.is_empty(), "{:?}", collected.failed);
+    assert!(collected
+        .output
+        .contains("monitorbot_collector_stale{collector=\"github_runners\"} 0"));
+
+    // after a restart GitHub is down, but the last good data is still exported
+    let github = MockGitHub::start().await;
+    github.push(
+        "/user",
+        MockResponse::json(200, json!({ "login": "user-1" })),
+    );
+    github.push("/rate_limit", MockResponse::raw(502, "Bad Gateway"));
+    github.push(
+        "/repos/rust-lang/rust/actions/runners?per_page=100",
+        MockResponse::raw(502, "Bad Gateway"),
+    );
+    let mut restarted = config(&github.url(), "token-1", "rust-lang/rust");
+    restarted.state_dir = Some(dir.clone());
+    let collected = collect(restarted, &[]).await;
+    assert_eq!(2, collected.failed.len(), "{:?}", collected.failed);
+
+    let output = &collected.output;
+    for expected in &[
+        "monitorbot_collector_stale{collector=\"github_rate_limit\"} 1",
+        "monitorbot_collector_stale{collector=\"github_runners\"} 1",
+        "monitorbot_github_rate_limit_remaining{product=\"core\",username=\"user-1\"} 4987",
+        "monitorbot_github_rate_limit_exhausted_total{product=\"code_search\",username=\"user-1\"} 1",
+        "monitorbot_gha_runner_busy{repo=\"rust-lang/rust\",runner=\"aws-x86_64-1\"} 1",
+    ] {
+        assert!(output.contains(expected), "missing {} in\n{}", expected, output);
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}